@@ -17,19 +17,15 @@ struct SpankHello {
 
 unsafe impl Plugin for SpankHello {
     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
-        // Register the --greet=name option
-        match spank.context()? {
-            Context::Local | Context::Remote => {
-                spank
-                    .register_option(
-                        SpankOption::new("greet")
-                            .takes_value("name")
-                            .usage("Greet [name] before running tasks"),
-                    )
-                    .wrap_err("Failed to register greet option")?;
-            }
-            _ => {}
-        }
+        // Register the --greet=name option for srun
+        spank
+            .register_option(
+                SpankOption::new("greet")
+                    .takes_value("name")
+                    .usage("Greet [name] before running tasks")
+                    .contexts(&[Context::Local]),
+            )
+            .wrap_err("Failed to register greet option")?;
         Ok(())
     }
     fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
@@ -1,6 +1,8 @@
 use eyre::{eyre, Report, WrapErr};
 use libc::{setpriority, PRIO_PROCESS};
-use slurm_spank::{Context, Plugin, SpankHandle, SpankOption, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+use slurm_spank::{
+    Context, OptionSource, Plugin, SpankHandle, SpankOption, SLURM_VERSION_NUMBER, SPANK_PLUGIN,
+};
 use std::error::Error;
 use tracing::{error, info};
 
@@ -10,50 +12,35 @@ const MIN_PRIO: i32 = -20;
 const PRIO_ENV_VAR: &str = "SLURM_RENICE";
 
 // All spank plugins must define this macro for the
-// Slurm plugin loader.
-SPANK_PLUGIN!(b"renice", SLURM_VERSION_NUMBER, SpankRenice);
+// Slurm plugin loader. The plugin instance is created
+// by SpankRenice::new when it is loaded by Slurm.
+SPANK_PLUGIN!(
+    b"renice",
+    SLURM_VERSION_NUMBER,
+    SpankRenice,
+    SpankRenice::new
+);
 
 struct SpankRenice {
     min_prio: i32,
     prio: Option<i32>,
 }
 
-// A default instance of the plugin is created when it
-// is loaded by Slurm
-impl Default for SpankRenice {
-    fn default() -> Self {
-        Self {
-            // Minimum allowable value for priority. May be
-            // set globally via plugin option min_prio=<prio>
-            min_prio: MIN_PRIO,
-            prio: None,
-        }
-    }
-}
-
 unsafe impl Plugin for SpankRenice {
     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
         // Don't do anything in slurmd/sbatch/salloc
         if spank.context()? == Context::Allocator || spank.context()? == Context::Slurmd {
             return Ok(());
         }
-        if spank.context()? == Context::Remote {
-            // Parse plugin configuration file
-            for arg in spank.plugin_argv().wrap_err("Invalid plugin argument")? {
-                match arg.strip_prefix("min_prio=") {
-                    Some(value) => {
-                        self.min_prio = parse_prio(value).wrap_err("Invalid min_prio")?
-                    }
-                    None => return Err(eyre!("Invalid plugin argument: {}", arg).into()),
-                }
-            }
-        }
-        // Provide a --renice=prio option to srun
+        // Provide a --renice=prio option to srun, which may also be set
+        // with the SLURM_RENICE env var
         spank
             .register_option(
                 SpankOption::new("renice")
                     .takes_value("prio")
-                    .usage("Re-nice job tasks to priority [prio]"),
+                    .usage("Re-nice job tasks to priority [prio]")
+                    .env(PRIO_ENV_VAR)
+                    .validate(|prio| parse_prio(prio).map(|_| ()).map_err(|e| e.to_string())),
             )
             .wrap_err("Failed to register renice option")?;
 
@@ -67,34 +54,28 @@ unsafe impl Plugin for SpankRenice {
         }
 
         let prio = spank
-            .get_option_value("renice")
+            .get_option_value_with_source("renice")
             .wrap_err("Failed to read --renice option")?;
 
-        let prio = match prio {
+        let (prio, source) = match prio {
             None => {
                 return Ok(());
             }
             Some(prio) => prio,
         };
 
-        self.set_prio(&prio, "--renice")
-            .wrap_err("Bad value for --renice")?;
+        let opt_name = match source {
+            OptionSource::Environment => PRIO_ENV_VAR,
+            _ => "--renice",
+        };
+
+        self.set_prio(&prio, opt_name)
+            .wrap_err_with(|| format!("Bad value for {}", opt_name))?;
 
         Ok(())
     }
 
     fn task_post_fork(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
-        if self.prio.is_none() {
-            // See if SLURM_RENICE env var is set by user
-            if let Some(prio) = spank
-                .getenv(PRIO_ENV_VAR)
-                .wrap_err(format!("Bad value for {}", PRIO_ENV_VAR))?
-            {
-                self.set_prio(&prio, PRIO_ENV_VAR)
-                    .wrap_err_with(|| format!("Bad value for {}", PRIO_ENV_VAR))?;
-            }
-        }
-
         if let Some(prio) = self.prio {
             let task_id = spank.task_global_id()?;
             let pid = spank.task_pid()?;
@@ -111,6 +92,27 @@ unsafe impl Plugin for SpankRenice {
 }
 
 impl SpankRenice {
+    fn new(spank: &mut SpankHandle) -> Result<Self, Report> {
+        // Minimum allowable value for priority. May be
+        // set globally via plugin option min_prio=<prio>
+        let mut min_prio = MIN_PRIO;
+
+        if spank.context()? == Context::Remote {
+            // Parse plugin configuration file
+            for arg in spank.plugin_argv().wrap_err("Invalid plugin argument")? {
+                match arg.strip_prefix("min_prio=") {
+                    Some(value) => min_prio = parse_prio(value).wrap_err("Invalid min_prio")?,
+                    None => return Err(eyre!("Invalid plugin argument: {}", arg)),
+                }
+            }
+        }
+
+        Ok(Self {
+            min_prio,
+            prio: None,
+        })
+    }
+
     fn set_prio(&mut self, prio: &str, opt_name: &str) -> Result<(), Report> {
         let prio = parse_prio(prio)?;
 
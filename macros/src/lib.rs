@@ -0,0 +1,216 @@
+//! Procedural macros for the slurm-spank crate
+//!
+//! This crate is an implementation detail of slurm-spank. Its macros are
+//! re-exported by slurm-spank and should be used from there.
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, ToTokens};
+use syn::meta::ParseNestedMeta;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, Expr, Item, LitByteStr, LitStr, Type};
+
+// Slurm does not enforce a limit but plugin names end up in log messages and
+// in option help so we keep them reasonably short.
+const PLUGIN_NAME_MAX_LEN: usize = 64;
+
+#[derive(Default)]
+struct PluginArgs {
+    name: Option<LitStr>,
+    version: Option<Expr>,
+    constructor: Option<Expr>,
+    options: Vec<StaticOption>,
+}
+
+// Option of the static spank_options table
+struct StaticOption {
+    name: LitStr,
+    arg: Option<LitStr>,
+    usage: Option<LitStr>,
+}
+
+impl StaticOption {
+    fn parse(meta: ParseNestedMeta) -> Result<Self, Error> {
+        let mut name = None;
+        let mut arg = None;
+        let mut usage = None;
+        let span = meta.path.span();
+        meta.parse_nested_meta(|meta| {
+            let field = if meta.path.is_ident("name") {
+                &mut name
+            } else if meta.path.is_ident("arg") {
+                &mut arg
+            } else if meta.path.is_ident("usage") {
+                &mut usage
+            } else {
+                return Err(
+                    meta.error("unsupported option field, expected `name`, `arg` or `usage`")
+                );
+            };
+            let value: LitStr = meta.value()?.parse()?;
+            if value.value().contains('\0') {
+                return Err(Error::new(
+                    value.span(),
+                    "option fields cannot contain NUL characters",
+                ));
+            }
+            *field = Some(value);
+            Ok(())
+        })?;
+
+        let name = name.ok_or_else(|| Error::new(span, "missing option name"))?;
+        if name.value().is_empty() {
+            return Err(Error::new(name.span(), "option name cannot be empty"));
+        }
+        Ok(StaticOption { name, arg, usage })
+    }
+}
+
+impl ToTokens for StaticOption {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let name = &self.name;
+        let arg = self.arg.iter();
+        let usage = self.usage.iter();
+        tokens.extend(quote!({ name: #name #(, arg: #arg)* #(, usage: #usage)* }));
+    }
+}
+
+/// Export a Plugin to make it available to the Slurm plugin loader
+///
+/// See the documentation of `slurm_spank::plugin` for details.
+#[proc_macro_attribute]
+pub fn plugin(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut plugin_args = PluginArgs::default();
+    let args_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            plugin_args.name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("version") {
+            plugin_args.version = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("constructor") {
+            plugin_args.constructor = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("option") {
+            plugin_args.options.push(StaticOption::parse(meta)?);
+            Ok(())
+        } else {
+            Err(meta.error(
+                "unsupported plugin argument, expected `name`, `version`, `constructor` or `option`",
+            ))
+        }
+    });
+    parse_macro_input!(args with args_parser);
+
+    let item = parse_macro_input!(input as Item);
+
+    // Always emit the original item so that errors in the attribute don't
+    // cause spurious errors about the plugin type being undefined.
+    let exports = expand(plugin_args, &item).unwrap_or_else(Error::into_compile_error);
+    quote!(#item #exports).into()
+}
+
+fn expand(args: PluginArgs, item: &Item) -> Result<TokenStream2, Error> {
+    let name = args.name.ok_or_else(|| {
+        Error::new(
+            Span::call_site(),
+            "missing plugin name, use #[plugin(name = \"...\")]",
+        )
+    })?;
+    let name_bytes = plugin_name_bytes(&name)?;
+    let name_len = name_bytes.value().len();
+
+    let version = match args.version {
+        Some(version) => quote!(#version),
+        None => quote!(::slurm_spank::SLURM_VERSION_NUMBER),
+    };
+
+    let plugin_ty = plugin_type(item)?;
+
+    let hooks = match args.constructor {
+        Some(constructor) => {
+            quote!(::slurm_spank::__spank_plugin_hooks!(#plugin_ty, #constructor);)
+        }
+        None => quote!(::slurm_spank::__spank_plugin_hooks!(#plugin_ty);),
+    };
+
+    let options = &args.options;
+    let static_options = if options.is_empty() {
+        quote!()
+    } else {
+        quote!(::slurm_spank::__spank_static_options!(#(#options),*);)
+    };
+
+    Ok(quote! {
+        const _: () = {
+            #[no_mangle]
+            pub static plugin_name: [u8; #name_len] = *#name_bytes;
+            #[no_mangle]
+            pub static mut plugin_type: [u8; 6] = *b"spank\0";
+            #[no_mangle]
+            pub static plugin_version: ::std::os::raw::c_uint = #version;
+
+            #hooks
+            #static_options
+        };
+    })
+}
+
+fn plugin_name_bytes(name: &LitStr) -> Result<LitByteStr, Error> {
+    let value = name.value();
+
+    if value.is_empty() {
+        return Err(Error::new(name.span(), "plugin name cannot be empty"));
+    }
+    if value.contains('\0') {
+        return Err(Error::new(
+            name.span(),
+            "plugin name cannot contain NUL characters",
+        ));
+    }
+    if value.len() > PLUGIN_NAME_MAX_LEN {
+        return Err(Error::new(
+            name.span(),
+            format!(
+                "plugin name cannot be longer than {} bytes",
+                PLUGIN_NAME_MAX_LEN
+            ),
+        ));
+    }
+
+    let mut bytes = value.into_bytes();
+    bytes.push(0);
+    Ok(LitByteStr::new(&bytes, name.span()))
+}
+
+fn plugin_type(item: &Item) -> Result<Type, Error> {
+    match item {
+        Item::Struct(item_struct) => {
+            if !item_struct.generics.params.is_empty() {
+                return Err(Error::new(
+                    item_struct.generics.span(),
+                    "plugin struct cannot be generic",
+                ));
+            }
+            let ident = &item_struct.ident;
+            Ok(syn::parse_quote!(#ident))
+        }
+        Item::Impl(item_impl) => match &item_impl.trait_ {
+            Some((None, path, _))
+                if path
+                    .segments
+                    .last()
+                    .is_some_and(|segment| segment.ident == "Plugin") =>
+            {
+                Ok((*item_impl.self_ty).clone())
+            }
+            _ => Err(Error::new(
+                item_impl.self_ty.span(),
+                "#[plugin] can only be applied to an `impl Plugin for ...` block",
+            )),
+        },
+        _ => Err(Error::new(
+            Span::call_site(),
+            "#[plugin] can only be applied to a struct or to an `impl Plugin for ...` block",
+        )),
+    }
+}
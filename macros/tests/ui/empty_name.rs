@@ -0,0 +1,4 @@
+#[slurm_spank_macros::plugin(name = "")]
+struct Plugin;
+
+fn main() {}
@@ -0,0 +1,5 @@
+// 65 bytes
+#[slurm_spank_macros::plugin(name = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")]
+struct Plugin;
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[slurm_spank_macros::plugin(name = "hello\0world")]
+struct Plugin;
+
+fn main() {}
@@ -0,0 +1,209 @@
+use crate::{Plugin, SpankHandle, SpankOption};
+use std::error::Error;
+
+type Callback<S> = Box<dyn FnMut(&mut S, &mut SpankHandle) -> Result<(), Box<dyn Error>>>;
+
+/// Closure-based alternative to implementing the [`Plugin`] trait
+///
+/// Options added with [`option`] are registered before the `init` callback is
+/// run, in all contexts. Each callback receives a mutable reference to the
+/// plugin state, which is `()` unless the builder was created with
+/// [`with_state`]. Callbacks which are not set do nothing.
+///
+/// A builder is exported with the [`SPANK_PLUGIN_BUILDER!`] macro, which also
+/// gives the plugin its name.
+///
+/// [`option`]: SpankPluginBuilder::option
+/// [`with_state`]: SpankPluginBuilder::with_state
+/// [`SPANK_PLUGIN_BUILDER!`]: crate::SPANK_PLUGIN_BUILDER
+///
+/// # Example
+///
+///```rust,no_run
+/// use slurm_spank::{
+///     spank_log_user, SpankOption, SpankPluginBuilder, SLURM_VERSION_NUMBER,
+///     SPANK_PLUGIN_BUILDER,
+/// };
+///
+/// SPANK_PLUGIN_BUILDER!(b"hello", SLURM_VERSION_NUMBER, hello);
+///
+/// fn hello() -> SpankPluginBuilder<Option<String>> {
+///     SpankPluginBuilder::with_state(None)
+///         .option(SpankOption::new("greet").takes_value("name"))
+///         .on_init_post_opt(|greet, spank| {
+///             *greet = spank.get_option_value("greet")?.map(|s| s.to_string());
+///             Ok(())
+///         })
+///         .on_user_init(|greet, _| {
+///             if let Some(name) = greet {
+///                 spank_log_user!("Hello {name}!");
+///             }
+///             Ok(())
+///         })
+/// }
+///```
+pub struct SpankPluginBuilder<S = ()> {
+    state: S,
+    options: Vec<SpankOption>,
+    init: Option<Callback<S>>,
+    job_prolog: Option<Callback<S>>,
+    init_post_opt: Option<Callback<S>>,
+    local_user_init: Option<Callback<S>>,
+    user_init: Option<Callback<S>>,
+    task_init_privileged: Option<Callback<S>>,
+    task_init: Option<Callback<S>>,
+    task_post_fork: Option<Callback<S>>,
+    task_exit: Option<Callback<S>>,
+    job_epilog: Option<Callback<S>>,
+    slurmd_exit: Option<Callback<S>>,
+    exit: Option<Callback<S>>,
+}
+
+impl SpankPluginBuilder {
+    /// Creates a plugin builder without state
+    pub fn new() -> Self {
+        Self::with_state(())
+    }
+}
+
+impl Default for SpankPluginBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+macro_rules! builder_callbacks {
+    ($($(#[$outer:meta])* $setter:ident => $cb:ident),*) => {
+        impl<S: 'static> SpankPluginBuilder<S> {
+            $(
+                $(#[$outer])*
+                pub fn $setter<F>(mut self, cb: F) -> Self
+                where
+                    F: FnMut(&mut S, &mut SpankHandle) -> Result<(), Box<dyn Error>> + 'static,
+                {
+                    self.$cb = Some(Box::new(cb));
+                    self
+                }
+            )*
+        }
+    };
+}
+
+builder_callbacks!(
+    /// Sets the callback called just after plugins are loaded (see [`Plugin::init`])
+    on_init => init,
+    /// Sets the callback called at the same time as the job prolog (see [`Plugin::job_prolog`])
+    on_job_prolog => job_prolog,
+    /// Sets the callback called after user options have been processed (see [`Plugin::init_post_opt`])
+    on_init_post_opt => init_post_opt,
+    /// Sets the callback called in local context before tasks are launched (see [`Plugin::local_user_init`])
+    on_local_user_init => local_user_init,
+    /// Sets the callback called after privileges are dropped (see [`Plugin::user_init`])
+    on_user_init => user_init,
+    /// Sets the callback called for each task before privileges are dropped (see [`Plugin::task_init_privileged`])
+    on_task_init_privileged => task_init_privileged,
+    /// Sets the callback called for each task just before execve (see [`Plugin::task_init`])
+    on_task_init => task_init,
+    /// Sets the callback called for each task from the parent process after fork (see [`Plugin::task_post_fork`])
+    on_task_post_fork => task_post_fork,
+    /// Sets the callback called for each task as its exit status is collected (see [`Plugin::task_exit`])
+    on_task_exit => task_exit,
+    /// Sets the callback called at the same time as the job epilog (see [`Plugin::job_epilog`])
+    on_job_epilog => job_epilog,
+    /// Sets the callback called when slurmd is shut down (see [`Plugin::slurmd_exit`])
+    on_slurmd_exit => slurmd_exit,
+    /// Sets the callback called before slurmstepd or srun exits (see [`Plugin::exit`])
+    on_exit => exit
+);
+
+impl<S: 'static> SpankPluginBuilder<S> {
+    /// Creates a plugin builder holding `state`
+    pub fn with_state(state: S) -> Self {
+        SpankPluginBuilder {
+            state,
+            options: Vec::new(),
+            init: None,
+            job_prolog: None,
+            init_post_opt: None,
+            local_user_init: None,
+            user_init: None,
+            task_init_privileged: None,
+            task_init: None,
+            task_post_fork: None,
+            task_exit: None,
+            job_epilog: None,
+            slurmd_exit: None,
+            exit: None,
+        }
+    }
+
+    /// Adds an option to register when the plugin is initialized
+    pub fn option(mut self, option: SpankOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn run(
+        cb: &mut Option<Callback<S>>,
+        state: &mut S,
+        spank: &mut SpankHandle,
+    ) -> Result<(), Box<dyn Error>> {
+        match cb {
+            Some(cb) => cb(state, spank),
+            None => Ok(()),
+        }
+    }
+}
+
+unsafe impl<S: 'static> Plugin for SpankPluginBuilder<S> {
+    fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        for option in std::mem::take(&mut self.options) {
+            spank.register_option(option)?;
+        }
+        Self::run(&mut self.init, &mut self.state, spank)
+    }
+
+    fn job_prolog(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.job_prolog, &mut self.state, spank)
+    }
+
+    fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.init_post_opt, &mut self.state, spank)
+    }
+
+    fn local_user_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.local_user_init, &mut self.state, spank)
+    }
+
+    fn user_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.user_init, &mut self.state, spank)
+    }
+
+    fn task_init_privileged(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.task_init_privileged, &mut self.state, spank)
+    }
+
+    fn task_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.task_init, &mut self.state, spank)
+    }
+
+    fn task_post_fork(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.task_post_fork, &mut self.state, spank)
+    }
+
+    fn task_exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.task_exit, &mut self.state, spank)
+    }
+
+    fn job_epilog(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.job_epilog, &mut self.state, spank)
+    }
+
+    fn slurmd_exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.slurmd_exit, &mut self.state, spank)
+    }
+
+    fn exit(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        Self::run(&mut self.exit, &mut self.state, spank)
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{slurm_spank_log, SpankError, SpankHandle};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Criterion identifying an executable in a [`CommandPolicy`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandMatch {
+    /// Matches an executable by absolute path, before or after symbolic links
+    /// are resolved
+    Path(PathBuf),
+    /// Matches an executable by file name, before or after symbolic links are
+    /// resolved
+    Name(OsString),
+    /// Matches an executable by the SHA-256 hash of its content
+    Sha256([u8; 32]),
+}
+
+impl CommandMatch {
+    /// Parses a match written as `path:PATH`, `name:NAME` or `sha256:HEX`
+    pub fn parse(s: &str) -> Result<Self, SpankError> {
+        let invalid = |e: &str| SpankError::InvalidPattern(s.to_string(), e.to_string());
+        match s.split_once(':') {
+            Some(("path", path)) if path.starts_with('/') => Ok(CommandMatch::Path(path.into())),
+            Some(("path", _)) => Err(invalid("path must be absolute")),
+            Some(("name", name)) if !name.is_empty() && !name.contains('/') => {
+                Ok(CommandMatch::Name(name.into()))
+            }
+            Some(("name", _)) => Err(invalid("invalid file name")),
+            Some(("sha256", hex)) => Self::parse_sha256(hex).ok_or_else(|| invalid("invalid hash")),
+            _ => Err(invalid("expected path:, name: or sha256:")),
+        }
+    }
+
+    fn parse_sha256(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(CommandMatch::Sha256(hash))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CommandAction {
+    Deny,
+    Redirect(PathBuf),
+}
+
+/// Policy denying or redirecting the commands run by tasks
+///
+/// The command of each task is resolved through the `PATH` of the job
+/// environment with [`SpankHandle::resolve_command`], then compared to the
+/// rules of the policy in the order in which they were added. The first
+/// matching rule applies:
+///
+/// - a denied command is reported to the user and the task fails to start
+/// - a redirected command is run through the redirection target, which
+///   receives the original command line as arguments, as with a
+///   [`TaskWrapper`]
+///
+/// Commands which cannot be resolved are left to fail on their own.
+///
+/// Only the command itself, the first element of the task argv, is checked.
+/// A denied program can still be started through another one, for instance
+/// with `bash -c mpirun` or from a script, so a policy guards against mistakes
+/// and is not a security boundary.
+///
+/// A policy is enforced with [`SpankHandle::enforce_command_policy`] from
+/// `task_init`.
+///
+/// [`TaskWrapper`]: crate::TaskWrapper
+///
+/// # Example
+///
+/// The following plugin forbids running `mpirun` from `srun`, and accepts
+/// additional rules from plugstack.conf (see
+/// [`CommandPolicy::from_plugin_args`]):
+///
+///```rust,no_run
+/// use slurm_spank::{
+///     CommandMatch, CommandPolicy, Plugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN,
+/// };
+/// use std::error::Error;
+///
+/// SPANK_PLUGIN!(b"commands", SLURM_VERSION_NUMBER, Commands);
+///
+/// #[derive(Default)]
+/// struct Commands {}
+///
+/// unsafe impl Plugin for Commands {
+///     fn task_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         let policy = CommandPolicy::from_plugin_args(&spank.plugin_argv()?)?
+///             .deny(CommandMatch::Name("mpirun".into()))
+///             .message("use srun to launch MPI applications");
+///         spank.enforce_command_policy(&policy)?;
+///         Ok(())
+///     }
+/// }
+///```
+#[derive(Debug, Clone, Default)]
+pub struct CommandPolicy {
+    rules: Vec<(CommandMatch, CommandAction)>,
+    message: Option<String>,
+}
+
+impl CommandPolicy {
+    /// Creates a policy which allows every command
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy from plugin arguments
+    ///
+    /// The following arguments are recognized. Other arguments starting with
+    /// `cmd_` are rejected, so that a misspelled rule is reported instead of
+    /// being silently ignored, and the remaining ones are ignored:
+    ///
+    /// - `cmd_deny=MATCH` denies commands matching `MATCH`
+    /// - `cmd_redirect=MATCH=TARGET` redirects commands matching `MATCH` to
+    ///   the absolute path `TARGET`
+    /// - `cmd_deny_message=MESSAGE` sets the message shown when a command is
+    ///   denied
+    ///
+    /// `MATCH` is parsed with [`CommandMatch::parse`].
+    pub fn from_plugin_args<S: AsRef<str>>(args: &[S]) -> Result<Self, SpankError> {
+        let mut policy = Self::new();
+        for arg in args {
+            let arg = arg.as_ref();
+            let invalid = |e: &str| SpankError::InvalidPluginArg(arg.to_string(), e.to_string());
+            let Some((key, value)) = arg.split_once('=') else {
+                if arg.starts_with("cmd_") {
+                    return Err(invalid("expected KEY=VALUE"));
+                }
+                continue;
+            };
+            policy = match key {
+                "cmd_deny" => policy.deny(CommandMatch::parse(value)?),
+                "cmd_redirect" => match value.split_once('=') {
+                    Some((m, target)) if target.starts_with('/') => {
+                        policy.redirect(CommandMatch::parse(m)?, target)
+                    }
+                    _ => {
+                        return Err(SpankError::InvalidPattern(
+                            value.to_string(),
+                            "expected MATCH=/path/to/target".to_string(),
+                        ))
+                    }
+                },
+                "cmd_deny_message" => policy.message(value),
+                _ if key.starts_with("cmd_") => return Err(invalid("unknown policy argument")),
+                _ => policy,
+            };
+        }
+        Ok(policy)
+    }
+
+    /// Denies commands matching `command`
+    pub fn deny(mut self, command: CommandMatch) -> Self {
+        self.rules.push((command, CommandAction::Deny));
+        self
+    }
+
+    /// Runs commands matching `command` through `target`
+    pub fn redirect<P: AsRef<Path>>(mut self, command: CommandMatch, target: P) -> Self {
+        self.rules.push((
+            command,
+            CommandAction::Redirect(target.as_ref().to_path_buf()),
+        ));
+        self
+    }
+
+    /// Sets the message shown to the user when a command is denied
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    // Returns the action of the first rule matching the executable at `path`
+    fn action(&self, path: &Path) -> Option<&CommandAction> {
+        let canonical = path.canonicalize().ok();
+        let paths = || std::iter::once(path).chain(canonical.as_deref());
+        let mut hash = None;
+
+        self.rules
+            .iter()
+            .find(|(command, _)| match command {
+                CommandMatch::Path(p) => paths().any(|path| path == p),
+                CommandMatch::Name(n) => {
+                    paths().any(|path| path.file_name() == Some(n.as_os_str()))
+                }
+                CommandMatch::Sha256(h) => {
+                    hash.get_or_insert_with(|| sha256(path)).as_ref() == Some(h)
+                }
+            })
+            .map(|(_, action)| action)
+    }
+}
+
+fn sha256(path: &Path) -> Option<[u8; 32]> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(hasher.finalize().into())
+}
+
+impl SpankHandle<'_> {
+    /// Denies or redirects the command of the current task according to
+    /// `policy`
+    ///
+    /// When the command is denied, a message is shown to the user and a
+    /// [`SpankError::CommandDenied`] error is returned, which should be
+    /// propagated so that the task does not start. This function also returns
+    /// an error if called outside of a task context.
+    ///
+    /// Only the command of the task is checked, not the programs it starts:
+    /// see [`CommandPolicy`].
+    pub fn enforce_command_policy(&self, policy: &CommandPolicy) -> Result<(), SpankError> {
+        let argv = self.job_argv_os()?;
+        let Some(command) = argv.first() else {
+            return Ok(());
+        };
+        let Some(path) = self.resolve_command(command)? else {
+            return Ok(());
+        };
+
+        match policy.action(&path) {
+            None => Ok(()),
+            Some(CommandAction::Deny) => {
+                let message = policy.message.as_deref().unwrap_or("command not allowed");
+                slurm_spank_log(&format!("{}: {}", command.to_string_lossy(), message));
+                Err(SpankError::CommandDenied(
+                    path.to_string_lossy().into_owned(),
+                ))
+            }
+            Some(CommandAction::Redirect(target)) => {
+                info!("Redirecting {} to {}", path.display(), target.display());
+                self.prepend_task_argv_os(vec![target.as_os_str()])
+            }
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use crate::{SpankError, SpankHandle};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// Snapshot of a job environment, as a map ordered by variable name
+///
+/// A `JobEnv` is obtained from [`SpankHandle::job_env_map`] or parsed from
+/// `KEY=VALUE` strings with [`JobEnv::parse`]. It is not linked to the job: it
+/// can be modified freely and compared to the snapshot it was cloned from with
+/// [`diff`], and the resulting changes applied to the job with
+/// [`SpankHandle::apply_env_changes`].
+///
+/// [`diff`]: JobEnv::diff
+///
+/// # Example
+///
+///```rust,no_run
+/// # use slurm_spank::SpankHandle;
+/// # use std::error::Error;
+/// # fn task_init(spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+/// let before = spank.job_env_map()?;
+/// let mut after = before.clone();
+/// after.set("OMP_NUM_THREADS", "4");
+/// after.remove("LD_PRELOAD");
+/// spank.apply_env_changes(&after.diff(&before))?;
+/// # Ok(())
+/// # }
+///```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobEnv {
+    vars: BTreeMap<OsString, OsString>,
+}
+
+impl JobEnv {
+    /// Creates an empty environment
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses an environment from `KEY=VALUE` entries
+    ///
+    /// Entries without a `=` are ignored. If a variable is defined more than
+    /// once, the first definition is kept, as getenv(3) would return it.
+    pub fn parse<I, S>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut vars = BTreeMap::new();
+        for entry in entries {
+            let entry = entry.as_ref().as_bytes();
+            if let Some(pos) = entry.iter().position(|&b| b == b'=') {
+                vars.entry(OsStr::from_bytes(&entry[..pos]).to_os_string())
+                    .or_insert_with(|| OsStr::from_bytes(&entry[pos + 1..]).to_os_string());
+            }
+        }
+        JobEnv { vars }
+    }
+
+    /// Returns the value of variable `name`
+    pub fn get<N: AsRef<OsStr>>(&self, name: N) -> Option<&OsStr> {
+        self.vars.get(name.as_ref()).map(OsString::as_os_str)
+    }
+
+    /// Returns whether variable `name` is set
+    pub fn contains<N: AsRef<OsStr>>(&self, name: N) -> bool {
+        self.vars.contains_key(name.as_ref())
+    }
+
+    /// Sets variable `name` to `value`
+    pub fn set<N: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, name: N, value: V) {
+        self.vars
+            .insert(name.as_ref().to_os_string(), value.as_ref().to_os_string());
+    }
+
+    /// Removes variable `name`, returning its previous value
+    pub fn remove<N: AsRef<OsStr>>(&mut self, name: N) -> Option<OsString> {
+        self.vars.remove(name.as_ref())
+    }
+
+    /// Iterates over the variables in name order
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.vars
+            .iter()
+            .map(|(name, value)| (name.as_os_str(), value.as_os_str()))
+    }
+
+    /// Returns the number of variables
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// Returns whether the environment is empty
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Returns the changes which turn `previous` into this environment
+    ///
+    /// Changes are ordered by variable name.
+    pub fn diff(&self, previous: &JobEnv) -> Vec<EnvChange> {
+        let unset = previous
+            .vars
+            .keys()
+            .filter(|name| !self.vars.contains_key(*name))
+            .map(|name| EnvChange::Unset(name.clone()));
+
+        let set = self
+            .vars
+            .iter()
+            .filter(|(name, value)| previous.vars.get(*name) != Some(value))
+            .map(|(name, value)| EnvChange::Set(name.clone(), value.clone()));
+
+        let mut changes: Vec<_> = unset.chain(set).collect();
+        changes.sort_by(|a, b| a.name().cmp(b.name()));
+        changes
+    }
+}
+
+/// Change to a single environment variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvChange {
+    /// Sets a variable to a value, overwriting any previous value
+    Set(OsString, OsString),
+    /// Unsets a variable
+    Unset(OsString),
+}
+
+impl EnvChange {
+    /// Returns the name of the variable affected by the change
+    pub fn name(&self) -> &OsStr {
+        match self {
+            EnvChange::Set(name, _) | EnvChange::Unset(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for EnvChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvChange::Set(name, value) => write!(
+                f,
+                "set {}={}",
+                name.to_string_lossy(),
+                value.to_string_lossy()
+            ),
+            EnvChange::Unset(name) => write!(f, "unset {}", name.to_string_lossy()),
+        }
+    }
+}
+
+/// Error returned by [`SpankHandle::apply_env_changes`] when some changes
+/// could not be applied
+#[derive(Debug, Clone)]
+pub struct EnvApplyError {
+    failures: Vec<(EnvChange, SpankError)>,
+}
+
+impl EnvApplyError {
+    /// Returns each change which failed along with its error
+    pub fn failures(&self) -> impl Iterator<Item = (&EnvChange, &SpankError)> {
+        self.failures.iter().map(|(change, error)| (change, error))
+    }
+}
+
+impl Error for EnvApplyError {}
+
+impl fmt::Display for EnvApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (change, error)) in self.failures.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "cannot {}: {}", change, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl SpankHandle<'_> {
+    /// Starts editing the list-valued variable `name` of the job environment
+    ///
+    /// See [`EnvList`]. The edits are only applicable in remote context.
+    pub fn env_list<N: AsRef<OsStr>>(&self, name: N) -> EnvList<'_, '_> {
+        self.new_env_list(name, false)
+    }
+
+    /// Starts editing the list-valued variable `name` of the job control
+    /// environment
+    ///
+    /// See [`EnvList`]. The edits are only applicable in local and allocator
+    /// contexts.
+    pub fn job_control_env_list<N: AsRef<OsStr>>(&self, name: N) -> EnvList<'_, '_> {
+        self.new_env_list(name, true)
+    }
+
+    fn new_env_list<N: AsRef<OsStr>>(&self, name: N, job_control: bool) -> EnvList<'_, '_> {
+        EnvList {
+            spank: self,
+            name: name.as_ref().to_os_string(),
+            separator: b':',
+            job_control,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Returns a snapshot of the job environment
+    ///
+    /// This function returns an error if called outside of remote context.
+    pub fn job_env_map(&self) -> Result<JobEnv, SpankError> {
+        self.job_env_os().map(JobEnv::parse)
+    }
+
+    /// Applies `changes` to the job environment with [`setenv`] and
+    /// [`unsetenv`]
+    ///
+    /// All changes are attempted, in order, even if some of them fail. The
+    /// returned error lists the changes which could not be applied.
+    ///
+    /// [`setenv`]: SpankHandle::setenv
+    /// [`unsetenv`]: SpankHandle::unsetenv
+    pub fn apply_env_changes(&self, changes: &[EnvChange]) -> Result<(), EnvApplyError> {
+        let failures: Vec<_> = changes
+            .iter()
+            .filter_map(|change| {
+                let res = match change {
+                    EnvChange::Set(name, value) => self.setenv(name, value, true),
+                    EnvChange::Unset(name) => self.unsetenv(name),
+                };
+                res.err().map(|e| (change.clone(), e))
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(EnvApplyError { failures })
+        }
+    }
+}
+
+pub(crate) enum ListEdit {
+    Prepend(OsString),
+    Append(OsString),
+    Remove(OsString),
+}
+
+/// Pending edits to a list-valued environment variable such as `PATH`
+///
+/// An `EnvList` is created with [`SpankHandle::env_list`] or
+/// [`SpankHandle::job_control_env_list`]. Edits are recorded in order and
+/// applied to the current value of the variable when [`apply`] is called.
+/// Components are compared and stored as raw bytes so that non-UTF-8 paths are
+/// kept intact.
+///
+/// Only the components named in the edits are affected: the rest of the
+/// current value, including empty components and duplicates, is kept as is.
+/// Adding a component which is already in the list leaves it in place rather
+/// than duplicating it. The variable is unset if the list ends up empty.
+///
+/// [`apply`]: EnvList::apply
+///
+/// # Example
+///
+///```rust,no_run
+/// # use slurm_spank::SpankHandle;
+/// # use std::error::Error;
+/// # fn user_init(spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+/// spank
+///     .env_list("LD_LIBRARY_PATH")
+///     .prepend("/opt/x/lib")
+///     .remove("/bad")
+///     .apply()?;
+/// # Ok(())
+/// # }
+///```
+pub struct EnvList<'a, 'b> {
+    spank: &'a SpankHandle<'b>,
+    name: OsString,
+    separator: u8,
+    job_control: bool,
+    edits: Vec<ListEdit>,
+}
+
+impl EnvList<'_, '_> {
+    /// Sets the separator between list components, `:` by default
+    pub fn separator(mut self, separator: u8) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Adds `component` at the beginning of the list, unless it already is in
+    /// the list
+    pub fn prepend<C: AsRef<OsStr>>(mut self, component: C) -> Self {
+        self.edits
+            .push(ListEdit::Prepend(component.as_ref().to_os_string()));
+        self
+    }
+
+    /// Adds `component` at the end of the list, unless it already is in the
+    /// list
+    pub fn append<C: AsRef<OsStr>>(mut self, component: C) -> Self {
+        self.edits
+            .push(ListEdit::Append(component.as_ref().to_os_string()));
+        self
+    }
+
+    /// Removes every occurrence of `component` from the list
+    pub fn remove<C: AsRef<OsStr>>(mut self, component: C) -> Self {
+        self.edits
+            .push(ListEdit::Remove(component.as_ref().to_os_string()));
+        self
+    }
+
+    /// Applies the edits to the variable
+    ///
+    /// This function returns an error if the environment is not accessible
+    /// from the current context: the job environment is only accessible in
+    /// remote context and the job control environment in local and allocator
+    /// contexts.
+    pub fn apply(self) -> Result<(), SpankError> {
+        let current = if self.job_control {
+            self.spank.job_control_getenv_os(&self.name)?
+        } else {
+            self.spank.getenv_os(&self.name)?
+        };
+
+        match (
+            edit_list(current.as_deref(), self.separator, &self.edits),
+            self.job_control,
+        ) {
+            (Some(value), false) => self.spank.setenv(&self.name, value, true),
+            (Some(value), true) => self.spank.job_control_setenv(&self.name, value, true),
+            (None, false) => self.spank.unsetenv(&self.name),
+            (None, true) => self.spank.job_control_unsetenv(&self.name),
+        }
+    }
+}
+
+// Returns `current` edited by `edits`, or None if the list ends up empty
+pub(crate) fn edit_list(
+    current: Option<&OsStr>,
+    separator: u8,
+    edits: &[ListEdit],
+) -> Option<OsString> {
+    // An empty value is an empty list rather than a single empty component
+    let mut components: Vec<&[u8]> = match current.filter(|c| !c.is_empty()) {
+        Some(current) => current.as_bytes().split(|&b| b == separator).collect(),
+        None => Vec::new(),
+    };
+
+    for edit in edits {
+        match edit {
+            ListEdit::Prepend(c) if !components.contains(&c.as_bytes()) => {
+                components.insert(0, c.as_bytes())
+            }
+            ListEdit::Append(c) if !components.contains(&c.as_bytes()) => {
+                components.push(c.as_bytes())
+            }
+            ListEdit::Prepend(_) | ListEdit::Append(_) => (),
+            ListEdit::Remove(c) => components.retain(|&e| e != c.as_bytes()),
+        }
+    }
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(OsString::from_vec(components.join(&separator)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(current: Option<&str>, edits: &[ListEdit]) -> Option<String> {
+        edit_list(current.map(OsStr::new), b':', edits).map(|v| v.into_string().unwrap())
+    }
+
+    fn prepend(c: &str) -> ListEdit {
+        ListEdit::Prepend(c.into())
+    }
+
+    fn append(c: &str) -> ListEdit {
+        ListEdit::Append(c.into())
+    }
+
+    #[test]
+    fn keeps_other_components() {
+        let current = Some("/a::/b:/a");
+        assert_eq!(
+            edit(current, &[prepend("/x"), append("/y")]).as_deref(),
+            Some("/x:/a::/b:/a:/y")
+        );
+        assert_eq!(
+            edit(current, &[ListEdit::Remove("/a".into())]).as_deref(),
+            Some(":/b")
+        );
+    }
+
+    #[test]
+    fn existing_components_stay_in_place() {
+        let current = Some("/a:/b");
+        assert_eq!(
+            edit(current, &[append("/a"), prepend("/b")]).as_deref(),
+            Some("/a:/b")
+        );
+    }
+
+    #[test]
+    fn empty_lists() {
+        assert_eq!(edit(None, &[append("/a")]).as_deref(), Some("/a"));
+        assert_eq!(edit(Some(""), &[prepend("/a")]).as_deref(), Some("/a"));
+        assert_eq!(edit(Some("/a"), &[ListEdit::Remove("/a".into())]), None);
+    }
+}
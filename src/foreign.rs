@@ -0,0 +1,243 @@
+use crate::{spank_sys, Plugin, SpankError, SpankHandle};
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+type ForeignCallback =
+    unsafe extern "C" fn(spank_sys::spank_t, c_int, *const *const c_char) -> c_int;
+
+macro_rules! foreign_callbacks {
+    ($($cb:ident => $symbol:literal),*) => {
+        struct ForeignCallbacks {
+            $($cb: Option<ForeignCallback>,)*
+        }
+
+        impl ForeignCallbacks {
+            fn resolve(handle: *mut c_void) -> Self {
+                ForeignCallbacks {
+                    $($cb: unsafe { dlsym(handle, $symbol) }
+                        .map(|sym| unsafe { std::mem::transmute::<*mut c_void, ForeignCallback>(sym) }),)*
+                }
+            }
+        }
+    };
+}
+
+foreign_callbacks!(
+    init => b"slurm_spank_init\0",
+    job_prolog => b"slurm_spank_job_prolog\0",
+    init_post_opt => b"slurm_spank_init_post_opt\0",
+    local_user_init => b"slurm_spank_local_user_init\0",
+    user_init => b"slurm_spank_user_init\0",
+    task_init_privileged => b"slurm_spank_task_init_privileged\0",
+    task_init => b"slurm_spank_task_init\0",
+    task_post_fork => b"slurm_spank_task_post_fork\0",
+    task_exit => b"slurm_spank_task_exit\0",
+    job_epilog => b"slurm_spank_job_epilog\0",
+    slurmd_exit => b"slurm_spank_slurmd_exit\0",
+    exit => b"slurm_spank_exit\0"
+);
+
+unsafe fn dlsym(handle: *mut c_void, symbol: &[u8]) -> Option<*mut c_void> {
+    let sym = libc::dlsym(handle, symbol.as_ptr() as *const c_char);
+    if sym.is_null() {
+        None
+    } else {
+        Some(sym)
+    }
+}
+
+fn dlerror() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(err) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// SPANK plugin written in C, loaded from a shared object
+///
+/// A `ForeignSpankPlugin` resolves the `slurm_spank_*` callbacks and the
+/// `spank_options` table of an existing SPANK plugin so that it can be run
+/// from a Rust plugin. Its [`Plugin`] callbacks forward the SPANK handle and
+/// the plugin arguments of the calling plugin to the corresponding C
+/// callbacks, if they are defined. A non-zero return value is turned into a
+/// [`SpankError::ForeignPluginCallback`] error.
+///
+/// The options of the C plugin's `spank_options` table are registered on
+/// behalf of the calling plugin before its `slurm_spank_init` callback is
+/// called. Their values are processed by the C plugin's own callbacks.
+///
+/// The shared object is never unloaded as Slurm may still hold pointers to
+/// its option callbacks.
+///
+/// # Example
+///
+/// The following plugin times the `task_init` callback of a legacy plugin:
+///
+///```rust,no_run
+/// use slurm_spank::{ForeignSpankPlugin, Plugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+/// use std::time::Instant;
+/// use tracing::info;
+///
+/// SPANK_PLUGIN!(b"legacy", SLURM_VERSION_NUMBER, Legacy, Legacy::new);
+///
+/// struct Legacy {
+///     inner: ForeignSpankPlugin,
+/// }
+///
+/// impl Legacy {
+///     fn new(_spank: &mut SpankHandle) -> Result<Self, Box<dyn Error>> {
+///         let inner = ForeignSpankPlugin::open("/usr/lib64/slurm/legacy.so")?;
+///         Ok(Legacy { inner })
+///     }
+/// }
+///
+/// unsafe impl Plugin for Legacy {
+///     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         self.inner.init(spank)
+///     }
+///
+///     fn task_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         let start = Instant::now();
+///         let res = self.inner.task_init(spank);
+///         info!("legacy task_init took {:?}", start.elapsed());
+///         res
+///     }
+/// }
+///```
+pub struct ForeignSpankPlugin {
+    path: PathBuf,
+    handle: *mut c_void,
+    callbacks: ForeignCallbacks,
+}
+
+impl ForeignSpankPlugin {
+    /// Loads the SPANK plugin at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SpankError> {
+        let path = path.as_ref();
+        let load_error =
+            |e: String| SpankError::ForeignPluginLoad(path.to_string_lossy().into_owned(), e);
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| SpankError::CStringError(path.to_string_lossy().into_owned()))?;
+
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(load_error(dlerror()));
+        }
+
+        let plugin = ForeignSpankPlugin {
+            path: path.to_path_buf(),
+            handle,
+            callbacks: ForeignCallbacks::resolve(handle),
+        };
+
+        match plugin.plugin_type() {
+            Some(b"spank") => Ok(plugin),
+            _ => Err(load_error("not a SPANK plugin".to_string())),
+        }
+    }
+
+    /// Returns the path from which the plugin was loaded
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the name of the plugin, as exported in its `plugin_name`
+    /// symbol
+    pub fn name(&self) -> Option<&str> {
+        self.c_str_symbol(b"plugin_name\0")
+            .and_then(|name| name.to_str().ok())
+    }
+
+    /// Returns the Slurm version for which the plugin was built, as exported
+    /// in its `plugin_version` symbol
+    pub fn version(&self) -> Option<u32> {
+        unsafe { dlsym(self.handle, b"plugin_version\0") }
+            .map(|version| unsafe { *(version as *const c_uint) })
+    }
+
+    fn plugin_type(&self) -> Option<&[u8]> {
+        self.c_str_symbol(b"plugin_type\0").map(CStr::to_bytes)
+    }
+
+    fn c_str_symbol(&self, symbol: &[u8]) -> Option<&CStr> {
+        unsafe { dlsym(self.handle, symbol) }
+            .map(|sym| unsafe { CStr::from_ptr(sym as *const c_char) })
+    }
+
+    /// Registers the options of the plugin's `spank_options` table
+    fn register_options(&self, spank: &mut SpankHandle) -> Result<(), SpankError> {
+        let Some(options) = (unsafe { dlsym(self.handle, b"spank_options\0") }) else {
+            return Ok(());
+        };
+        let mut option = options as *mut spank_sys::spank_option;
+
+        // The table is terminated by an entry with a NULL name
+        while !unsafe { (*option).name }.is_null() {
+            let mut c_spank_opt = unsafe { *option };
+            match unsafe { spank_sys::spank_option_register(spank.spank, &mut c_spank_opt) } {
+                spank_sys::ESPANK_SUCCESS => (),
+                e => return Err(SpankError::from_spank("spank_option_register", e)),
+            }
+            option = unsafe { option.add(1) };
+        }
+        Ok(())
+    }
+
+    fn call(
+        &self,
+        name: &str,
+        cb: Option<ForeignCallback>,
+        spank: &mut SpankHandle,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(cb) = cb else {
+            return Ok(());
+        };
+        match unsafe { cb(spank.spank, spank.argc, spank.argv) } {
+            0 => Ok(()),
+            rc => Err(Box::new(SpankError::ForeignPluginCallback(
+                name.to_string(),
+                rc,
+            ))),
+        }
+    }
+}
+
+macro_rules! forward_callbacks {
+    ($($cb:ident),*) => {
+        $(
+            fn $cb(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+                self.call(concat!("slurm_spank_", stringify!($cb)), self.callbacks.$cb, spank)
+            }
+        )*
+    };
+}
+
+unsafe impl Plugin for ForeignSpankPlugin {
+    fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        self.register_options(spank)?;
+        self.call("slurm_spank_init", self.callbacks.init, spank)
+    }
+
+    forward_callbacks!(
+        job_prolog,
+        init_post_opt,
+        local_user_init,
+        user_init,
+        task_init_privileged,
+        task_init,
+        task_post_fork,
+        task_exit,
+        job_epilog,
+        slurmd_exit,
+        exit
+    );
+}
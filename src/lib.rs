@@ -8,7 +8,7 @@
 //!
 //!To create a SPANK plugin using this crate, you need to define a struct for
 //!which you implement the [`Plugin`] trait and to make it available as a SPANK
-//!plugin using the [`SPANK_PLUGIN!`] macro.
+//!plugin using the [`SPANK_PLUGIN!`] macro or the [`plugin`] attribute.
 //!
 //!The methods of the Plugin trait correspond to the callbacks defined by the
 //!SPANK API such as [`init_post_opt`], [`task_post_fork`] etc. These methods
@@ -58,12 +58,12 @@
 //! slurm-spank = "0.3"
 //! tracing = "0.1.37"
 //!```
-use lazy_static::lazy_static;
 use libc::{gid_t, pid_t, uid_t};
 use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fmt;
@@ -72,28 +72,61 @@ use std::os::unix::ffi::OsStrExt;
 use std::panic::catch_unwind;
 use std::panic::UnwindSafe;
 use std::ptr;
-use std::sync::Mutex;
-use tracing::{error, span};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use tracing::{debug, error, span};
 use tracing_core::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::{
     format::Writer, layer, FmtContext, FormatEvent, FormatFields, FormattedFields,
 };
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::registry::LookupSpan;
-use tracing_subscriber::{EnvFilter, Registry};
-
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+mod builder;
+mod command;
+mod env;
+mod foreign;
+mod mount;
+mod policy;
+mod profile;
+mod secrets;
 #[doc(hidden)]
 pub mod spank_sys;
+mod stack;
+#[cfg(feature = "serde")]
+mod state;
+mod wrapper;
+
+pub use builder::SpankPluginBuilder;
 #[doc(hidden)]
 pub use byte_strings;
+pub use command::{CommandMatch, CommandPolicy};
+pub use env::{EnvApplyError, EnvChange, EnvList, JobEnv};
+pub use foreign::ForeignSpankPlugin;
+pub use mount::{JobDir, MountNamespace};
+pub use policy::EnvPolicy;
+pub use profile::EnvProfile;
+pub use secrets::SecretStore;
+pub use stack::{PluginStack, StackError, StackErrorMode};
+#[cfg(feature = "serde")]
+pub use state::MAX_STATE_SIZE;
+pub use wrapper::TaskWrapper;
 
 /// Handle to the Slurm interface exposed to SPANK plugins. It provides methods
 /// to query Slurm from a plugin.
 pub struct SpankHandle<'a> {
     spank: spank_sys::spank_t,
+    plugin_name: &'a str,
     argc: c_int,
     argv: *const *const c_char,
     opt_cache: &'a mut OptionCache,
+    // Prepended to the names of the options registered and queried through
+    // this handle, e.g. by PluginStack
+    option_prefix: Option<String>,
+    // Name of the PluginStack module using this handle, if any
+    module: Option<String>,
 }
 
 macro_rules! spank_item_getter {
@@ -152,6 +185,27 @@ fn os_value_to_lossy(value: Cow<'_, OsStr>) -> Cow<'_, str> {
         },
     }
 }
+
+// Logs an error from option resolution for the accessors which cannot return
+// errors
+fn log_option_error<T>(res: Result<Option<T>, SpankError>) -> Option<T> {
+    res.unwrap_or_else(|e| {
+        spank_log_error!("{}", e);
+        None
+    })
+}
+
+// Checks `value` with the validator of an option, if any
+fn validate_option_value(settings: &OptionSettings, value: &OsStr) -> Result<(), String> {
+    let Some(validator) = &settings.validator else {
+        return Ok(());
+    };
+    match value.to_str() {
+        Some(value) => validator(value),
+        None => Err("value is not valid UTF-8".to_string()),
+    }
+}
+
 fn os_value_to_str(value: Cow<'_, OsStr>) -> Result<Cow<'_, str>, SpankError> {
     match value {
         Cow::Borrowed(value) => Ok(Cow::from(
@@ -176,7 +230,79 @@ fn os_value_to_str(value: Cow<'_, OsStr>) -> Result<Cow<'_, str>, SpankError> {
 #[doc(hidden)]
 pub struct OptionCache {
     pub options: Vec<String>,
-    pub values: HashMap<String, Option<OsString>>,
+    // Values of each occurrence of the options, in command-line order
+    pub values: HashMap<String, Vec<OptionOccurrence>>,
+    pub settings: HashMap<String, OptionSettings>,
+    // Index of the plugin instance owning these options
+    pub instance: usize,
+    // Whether option names are prefixed with the plugin name
+    pub prefix_options: bool,
+}
+
+type OptionValidatorFn = dyn Fn(&str) -> Result<(), String>;
+
+// Behaviour of an option beyond what is registered with Slurm
+#[derive(Default)]
+#[doc(hidden)]
+pub struct OptionSettings {
+    validator: Option<Box<OptionValidatorFn>>,
+    env: Option<String>,
+    plugin_arg: Option<String>,
+    default: Option<String>,
+    // Set at registration, for the help of the plugin
+    arginfo: Option<String>,
+    usage: Option<String>,
+    builtin: Option<BuiltinOption>,
+    // Name of the option for which this option is an alias
+    alias_of: Option<String>,
+    deprecated: Option<String>,
+    // Whether the deprecation warning was already displayed
+    deprecation_warned: bool,
+}
+
+struct OptionAlias {
+    name: String,
+    deprecated: Option<String>,
+}
+
+impl fmt::Debug for OptionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OptionSettings")
+            .field("validator", &self.validator.is_some())
+            .field("env", &self.env)
+            .field("plugin_arg", &self.plugin_arg)
+            .field("default", &self.default)
+            .field("arginfo", &self.arginfo)
+            .field("usage", &self.usage)
+            .field("builtin", &self.builtin)
+            .field("alias_of", &self.alias_of)
+            .field("deprecated", &self.deprecated)
+            .finish()
+    }
+}
+
+// Options registered by SpankHandle::register_builtin_options, which are
+// handled by the crate as soon as they are parsed
+#[derive(Debug, Clone)]
+enum BuiltinOption {
+    // Tracing filter directives are restricted to spans matching `scope`
+    Debug { scope: String },
+    Help { plugin_name: String },
+}
+
+/// Where the value of an option was found
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OptionSource {
+    /// The option was given on the srun, sbatch or salloc command line
+    CommandLine,
+    /// The value was read from the environment variable set with
+    /// [`SpankOption::env`]
+    Environment,
+    /// The value was read from the plugin argument set with
+    /// [`SpankOption::default_from_plugin_arg`]
+    PluginArgument,
+    /// The value was set with [`SpankOption::default`]
+    Default,
 }
 
 impl SpankHandle<'_> {
@@ -188,26 +314,177 @@ impl SpankHandle<'_> {
         })
     }
 
+    /// Returns the name of the calling plugin
+    pub fn plugin_name(&self) -> &str {
+        self.plugin_name
+    }
+
+    // Returns the full name of option `name` in the current option namespace
+    fn option_name<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        let name = match &self.option_prefix {
+            Some(prefix) => Cow::from(format!("{}-{}", prefix, name)),
+            None => Cow::from(name),
+        };
+        if self.opt_cache.prefix_options {
+            Cow::from(format!("{}-{}", self.plugin_name, name))
+        } else {
+            name
+        }
+    }
+
+    /// Prefixes the names of the options of the plugin with the plugin name
+    ///
+    /// Once this function is called, an option registered as `prio` by the
+    /// `renice` plugin is available as `--renice-prio` and is still queried
+    /// as `prio` by the plugin. This avoids clashes with the options of other
+    /// plugins, as all SPANK options share the same namespace. Like
+    /// register_option, this function must be called from `init()` in all
+    /// contexts, before any option is registered.
+    pub fn prefix_option_names(&mut self) {
+        self.opt_cache.prefix_options = true;
+    }
+
+    /// Returns the full names of the options registered by the plugin
+    ///
+    /// This is useful to diagnose conflicts with the options of other plugins.
+    pub fn registered_options(&self) -> impl Iterator<Item = &str> {
+        self.opt_cache.options.iter().map(String::as_str)
+    }
+
+    // Runs `func` on behalf of the PluginStack module `module`, with options
+    // namespaced under its name, nested within the current namespace if there
+    // is one
+    pub(crate) fn with_option_prefix<R>(
+        &mut self,
+        module: &str,
+        func: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let prefix = match &self.option_prefix {
+            Some(current) => format!("{}-{}", current, module),
+            None => module.to_string(),
+        };
+        let previous = self.option_prefix.replace(prefix);
+        let previous_module = self.module.replace(module.to_string());
+        let res = func(self);
+        self.option_prefix = previous;
+        self.module = previous_module;
+        res
+    }
+
     /// Registers a plugin-provided option dynamically. This function is only
     /// valid when called from a plugin's `init()`, and must be guaranteed to be
     /// called in all contexts in which it is used (local, remote, allocator).
+    ///
+    /// If the option was restricted with [`SpankOption::contexts`], it is not
+    /// registered in the local and allocator contexts which are not listed, so
+    /// that it is hidden from the help of the corresponding commands and
+    /// rejected by them.
+    ///
+    /// A [`SpankError::OptionExists`] error is returned if an option with the
+    /// same name was already registered by the plugin, including by another
+    /// instance of the plugin. Slurm doesn't report clashes with the options
+    /// of other plugins as errors: it logs a message and disables the option,
+    /// which plugins cannot detect. To help diagnose such clashes, the options
+    /// registered by the plugin are logged at the debug level once `init()`
+    /// returns (see [`SpankHandle::registered_options`]). Use
+    /// prefix_option_names to avoid such clashes.
     pub fn register_option(&mut self, spank_opt: SpankOption) -> Result<(), SpankError> {
-        let arginfo = match &spank_opt.arginfo {
+        let context = self.context()?;
+        if let Some(contexts) = &spank_opt.contexts {
+            if matches!(context, Context::Local | Context::Allocator)
+                && !contexts.contains(&context)
+            {
+                return Ok(());
+            }
+        }
+
+        let opt_name = self.option_name(&spank_opt.name).into_owned();
+        let usage = spank_opt
+            .context_usage
+            .iter()
+            .find(|(usage_context, _)| *usage_context == context)
+            .map(|(_, usage)| usage)
+            .or(spank_opt.usage.as_ref())
+            .cloned();
+
+        let mut settings = spank_opt.settings;
+        settings.arginfo = spank_opt.arginfo.clone();
+        settings.usage = usage;
+
+        // Report invalid plugin arguments and defaults when the plugin is
+        // loaded rather than when the option is used
+        let plugin_arg = settings.plugin_arg.as_ref().and_then(|key| {
+            let value = self.plugin_arg_value_os(key)?;
+            Some((value, format!("plugin argument {}", key)))
+        });
+        let default = settings
+            .default
+            .as_deref()
+            .map(|value| (OsStr::new(value), "default value".to_string()));
+        for (value, origin) in plugin_arg.into_iter().chain(default) {
+            validate_option_value(&settings, value).map_err(|e| {
+                SpankError::InvalidOptionValue(opt_name.clone(), format!("{} (from {})", e, origin))
+            })?;
+        }
+
+        self.register_named_option(opt_name.clone(), spank_opt.optional_value, settings)?;
+
+        // Aliases are registered as separate options whose values are stored
+        // under the name of the option
+        for alias in spank_opt.aliases {
+            let alias_name = self.option_name(&alias.name).into_owned();
+            let usage = match &alias.deprecated {
+                Some(msg) => format!("Deprecated alias of --{}: {}", opt_name, msg),
+                None => format!("Alias of --{}", opt_name),
+            };
+            let settings = OptionSettings {
+                arginfo: spank_opt.arginfo.clone(),
+                usage: Some(usage),
+                alias_of: Some(opt_name.clone()),
+                deprecated: alias.deprecated,
+                ..Default::default()
+            };
+            self.register_named_option(alias_name, spank_opt.optional_value, settings)?;
+        }
+        Ok(())
+    }
+
+    fn register_named_option(
+        &mut self,
+        opt_name: String,
+        optional_value: bool,
+        settings: OptionSettings,
+    ) -> Result<(), SpankError> {
+        let arginfo = match &settings.arginfo {
             None => None,
             Some(info) => Some(CString::new(info as &str).map_err(|_| SpankError::from_str(info))?),
         };
-        let name = CString::new(&spank_opt.name as &str)
-            .map_err(|_| SpankError::from_str(&spank_opt.name))?;
-        let usage = match spank_opt.usage {
+        let name = CString::new(&opt_name as &str).map_err(|_| SpankError::from_str(&opt_name))?;
+
+        // Slurm only logs a message and disables the option when it is
+        // registered twice, so check for clashes between the instances of the
+        // plugin ourselves
+        let exists = REGISTERED_OPTIONS
+            .with(|names| names.contains(&opt_name))
+            .unwrap_or(false);
+        if exists {
+            return Err(SpankError::OptionExists { name: opt_name });
+        }
+
+        let usage = match &settings.usage {
             None => None,
             Some(usage) => {
-                Some(CString::new(&usage as &str).map_err(|_| SpankError::from_str(&usage))?)
+                Some(CString::new(usage as &str).map_err(|_| SpankError::from_str(usage))?)
             }
         };
 
         let mut c_spank_opt = spank_sys::spank_option {
             name: name.as_ptr(),
-            has_arg: arginfo.is_some() as i32,
+            has_arg: match (&arginfo, optional_value) {
+                (None, _) => 0,
+                (Some(_), false) => 1,
+                (Some(_), true) => 2,
+            },
             cb: Some(spank_option_callback),
             arginfo: match arginfo {
                 Some(ref arginfo) => arginfo.as_ptr(),
@@ -217,23 +494,68 @@ impl SpankHandle<'_> {
                 Some(ref usage) => usage.as_ptr(),
                 None => ptr::null(),
             },
-            val: self
-                .opt_cache
-                .options
-                .len()
-                .try_into()
+            val: option_val(self.opt_cache.instance, self.opt_cache.options.len())
                 .expect("Argument table overflow"),
         };
 
         match unsafe { spank_sys::spank_option_register(self.spank, &mut c_spank_opt) } {
             spank_sys::ESPANK_SUCCESS => {
-                self.opt_cache.options.push(spank_opt.name);
+                self.opt_cache.settings.insert(opt_name.clone(), settings);
+                REGISTERED_OPTIONS.with(|names| names.insert(opt_name.clone()));
+                self.opt_cache.options.push(opt_name);
                 Ok(())
             }
             e => Err(SpankError::from_spank("spank_option_register", e)),
         }
     }
 
+    /// Registers the builtin `--<plugin>-debug[=filter]` and `--<plugin>-help`
+    /// options, where `<plugin>` is the name of the plugin
+    ///
+    /// `--<plugin>-debug` adds `filter` to the filter of the tracing subscriber
+    /// configured by the default [`Plugin::setup`], using the [`EnvFilter`]
+    /// syntax without span filters, or `debug` if no filter is given. The
+    /// filter only applies to the events logged by the callbacks of the
+    /// plugin, or of the [`PluginStack`] module which registered the option,
+    /// so that other plugins keep their log level. As the option is forwarded
+    /// to slurmstepd, this raises the log level of the plugin for a single
+    /// job, both in srun/sbatch and on the nodes.
+    ///
+    /// `--<plugin>-help` displays the options registered by the plugin, along
+    /// with their environment variables and defaults. Slurm then reports the
+    /// option as invalid so that srun, sbatch or salloc exit with an error
+    /// instead of running the job. Options registered after this function is
+    /// called are also displayed.
+    ///
+    /// Like register_option, this function must be called from `init()` in
+    /// all contexts.
+    pub fn register_builtin_options(&mut self) -> Result<(), SpankError> {
+        let plugin_name = self.plugin_name.to_string();
+        // Don't prefix the name of the plugin twice
+        let prefix = if self.opt_cache.prefix_options {
+            String::new()
+        } else {
+            format!("{}-", plugin_name)
+        };
+
+        let mut debug = SpankOption::new(&format!("{}debug", prefix))
+            .takes_optional_value("filter")
+            .usage("Enable debug logs of the plugin, or the logs selected by [filter]");
+        // Events are selected by the span of the callbacks of the plugin or
+        // module, see make_cb_span and PluginStack
+        let scope = match &self.module {
+            Some(module) => format!("module{{name=^{}$}}", regex::escape(module)),
+            None => format!("spank{{id=^{}$}}", regex::escape(&plugin_name)),
+        };
+        debug.settings.builtin = Some(BuiltinOption::Debug { scope });
+        self.register_option(debug)?;
+
+        let mut help = SpankOption::new(&format!("{}help", prefix))
+            .usage("Display detailed help about the options of the plugin and exit");
+        help.settings.builtin = Some(BuiltinOption::Help { plugin_name });
+        self.register_option(help)
+    }
+
     /// Returns the list of arguments configured in the `plugstack.conf` file
     /// for this plugin
     pub fn plugin_argv(&self) -> Result<Vec<&str>, SpankError> {
@@ -568,8 +890,12 @@ impl SpankHandle<'_> {
     /// will be replaced with � (U+FFFD). If the option was specified multiple
     /// times, this function returns the last value provided.
     ///
+    /// Values which are not given on the command line are resolved as with
+    /// get_option_value_os.
+    ///
     /// *WARNING*: If options have not yet been processed (e.g in init callbacks
-    /// or all slurmd contexts), this function will always return None.
+    /// or all slurmd contexts), this function will only return values from the
+    /// plugin arguments or default values.
     ///
     /// *WARNING*: This function always returns None for options which don't
     /// take values (flag options created without takes_value()) no matter whether
@@ -585,16 +911,20 @@ impl SpankHandle<'_> {
     /// the option was specified multiple times, it returns the last value
     /// provided.
     ///
+    /// Values which are not given on the command line are resolved as with
+    /// get_option_value_os.
+    ///
     /// *WARNING*: If options have not yet been processed (e.g in init callbacks
-    /// or all slurmd contexts), this function will always return None.
+    /// or all slurmd contexts), this function will only return values from the
+    /// plugin arguments or default values.
     ///
     /// *WARNING*: This function always returns None for options which don't
     /// take values (flag options created without takes_value()) no matter whether
     /// they were used or not. To check whether a flag was set, use
     /// is_option_set.
     pub fn get_option_value(&self, name: &str) -> Result<Option<Cow<'_, str>>, SpankError> {
-        match self.get_option_value_os(name) {
-            Some(val) => Ok(Some(os_value_to_str(val)?)),
+        match self.resolve_option_os(name)? {
+            Some((val, _)) => Ok(Some(os_value_to_str(val)?)),
             None => Ok(None),
         }
     }
@@ -602,16 +932,123 @@ impl SpankHandle<'_> {
     /// Returns the value set for the option `name` as an OsString
     ///
     /// If the option was specified multiple times, it returns the last value
-    /// provided.
+    /// provided. If the option was not given on the command line, its value is
+    /// resolved from, in order:
+    /// - the environment variable set with [`SpankOption::env`]
+    /// - the plugin argument set with [`SpankOption::default_from_plugin_arg`]
+    /// - the default value set with [`SpankOption::default`]
+    ///
+    /// Values from these sources are checked with the validator set with
+    /// [`SpankOption::validate`]. As this function cannot return an error, an
+    /// invalid value is logged and None is returned: use get_option_value to
+    /// handle such errors.
     ///
     /// *WARNING*: If options have not yet been processed (e.g in init callbacks
-    /// or all slurmd contexts), this function will always return None.
+    /// or all slurmd contexts), this function will only return values from the
+    /// plugin arguments or default values.
     ///
     /// *WARNING*: This function always returns None for options which don't
     /// take values (flag options created without takes_value()) no matter whether
     /// they were used or not. To check whether a flag was set, use
-    /// get_option_count.
+    /// is_option_set.
     pub fn get_option_value_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        log_option_error(self.resolve_option_os(name)).map(|(value, _)| value)
+    }
+
+    /// Returns the value set for the option `name` as a String, along with
+    /// where it was found
+    ///
+    /// Values are resolved as with get_option_value_os. An error is returned
+    /// if the value is rejected by the validator of the option or cannot be
+    /// converted to a String.
+    pub fn get_option_value_with_source(
+        &self,
+        name: &str,
+    ) -> Result<Option<(Cow<'_, str>, OptionSource)>, SpankError> {
+        match self.resolve_option_os(name)? {
+            Some((value, source)) => Ok(Some((os_value_to_str(value)?, source))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns where the value of option `name` was found, if any
+    ///
+    /// Values are resolved as with get_option_value_os.
+    pub fn get_option_source(&self, name: &str) -> Option<OptionSource> {
+        log_option_error(self.resolve_option_os(name)).map(|(_, source)| source)
+    }
+
+    fn resolve_option_os(
+        &self,
+        name: &str,
+    ) -> Result<Option<(Cow<'_, OsStr>, OptionSource)>, SpankError> {
+        let name: &str = &self.option_name(name);
+
+        if let Some(value) = self.command_line_value_os(name) {
+            return Ok(Some((value, OptionSource::CommandLine)));
+        }
+        self.fallback_option_os(name)
+    }
+
+    // Returns the value of option `name` from the first source after the
+    // command line where it is found, checked with the validator of the option
+    fn fallback_option_os(
+        &self,
+        name: &str,
+    ) -> Result<Option<(Cow<'_, OsStr>, OptionSource)>, SpankError> {
+        let Some(settings) = self.opt_cache.settings.get(name) else {
+            return Ok(None);
+        };
+
+        let env_value = settings.env.as_ref().and_then(|var| {
+            let value = match self.context() {
+                Ok(Context::Local | Context::Allocator) => std::env::var_os(var),
+                Ok(Context::Remote) => self.getenv_os(var).ok().flatten(),
+                _ => None,
+            };
+            value.map(|value| (Cow::from(value), OptionSource::Environment, var))
+        });
+        let value = env_value
+            .or_else(|| {
+                let key = settings.plugin_arg.as_ref()?;
+                self.plugin_arg_value_os(key)
+                    .map(|value| (Cow::from(value), OptionSource::PluginArgument, key))
+            })
+            .or_else(|| {
+                let value = settings.default.as_ref()?;
+                Some((Cow::from(OsStr::new(value)), OptionSource::Default, value))
+            });
+
+        let Some((value, source, origin)) = value else {
+            return Ok(None);
+        };
+        match validate_option_value(settings, &value) {
+            Ok(()) => Ok(Some((value, source))),
+            Err(e) => {
+                let origin = match source {
+                    OptionSource::Environment => format!("environment variable {}", origin),
+                    OptionSource::PluginArgument => format!("plugin argument {}", origin),
+                    _ => "default value".to_string(),
+                };
+                Err(SpankError::InvalidOptionValue(
+                    name.to_string(),
+                    format!("{} (from {})", e, origin),
+                ))
+            }
+        }
+    }
+
+    // Returns the value of the last `key=value` plugin argument
+    fn plugin_arg_value_os(&self, key: &str) -> Option<&OsStr> {
+        let prefix = format!("{}=", key);
+        self.argv_to_vec_os(self.argc as usize, self.argv)
+            .into_iter()
+            .rev()
+            .find_map(|arg| arg.as_bytes().strip_prefix(prefix.as_bytes()))
+            .map(OsStr::from_bytes)
+    }
+
+    fn command_line_value_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
         match self.context() {
             Ok(Context::JobScript) => self
                 .getopt_os(name)
@@ -619,7 +1056,13 @@ impl SpankHandle<'_> {
                 .map(|opt| opt.map(Cow::from))
                 .unwrap_or(None),
             _ => {
-                if let Some(Some(ref value)) = self.opt_cache.values.get(name) {
+                if let Some(Some(ref value)) = self
+                    .opt_cache
+                    .values
+                    .get(name)
+                    .and_then(|v| v.last())
+                    .map(|occurrence| &occurrence.value)
+                {
                     Some(Cow::from(value))
                 } else {
                     None
@@ -628,19 +1071,139 @@ impl SpankHandle<'_> {
         }
     }
 
-    /// Returns whether an option was set
+    /// Returns the values of all occurrences of option `name` as lossy Strings
     ///
-    /// Use this function to process flag options.
+    /// Values are returned in the order in which they were given on the
+    /// command line. If the option was not given on the command line, the
+    /// value resolved as with get_option_value_os is returned, if any. If a
+    /// value contains invalid UTF-8 code points, those invalid points will be
+    /// replaced with � (U+FFFD).
+    ///
+    /// *WARNING*: Slurm only forwards the last value of each option to
+    /// slurmstepd and to the prolog/epilog, so at most one value is returned
+    /// in the Remote and JobScript contexts.
+    pub fn get_option_values_lossy(&self, name: &str) -> Vec<Cow<'_, str>> {
+        self.get_option_values_os(name)
+            .into_iter()
+            .map(os_value_to_lossy)
+            .collect()
+    }
+
+    /// Returns the values of all occurrences of option `name` as Strings
+    ///
+    /// Values are returned in the order in which they were given on the
+    /// command line. If the option was not given on the command line, the
+    /// value resolved as with get_option_value is returned, if any. An error
+    /// is returned if that value is rejected by the validator of the option or
+    /// if a value cannot be converted to a String.
+    ///
+    /// *WARNING*: Slurm only forwards the last value of each option to
+    /// slurmstepd and to the prolog/epilog, so at most one value is returned
+    /// in the Remote and JobScript contexts.
+    pub fn get_option_values(&self, name: &str) -> Result<Vec<Cow<'_, str>>, SpankError> {
+        self.option_values_os(name)?
+            .into_iter()
+            .map(os_value_to_str)
+            .collect()
+    }
+
+    /// Returns the values of all occurrences of option `name` as OsStrings
+    ///
+    /// Values are returned in the order in which they were given on the
+    /// command line. Occurrences without a value are skipped, use option_count
+    /// to count flags. If the option was not given on the command line, the
+    /// value resolved as with get_option_value_os is returned, if any.
+    ///
+    /// *WARNING*: Slurm only forwards the last value of each option to
+    /// slurmstepd and to the prolog/epilog, so at most one value is returned
+    /// in the Remote and JobScript contexts.
+    pub fn get_option_values_os(&self, name: &str) -> Vec<Cow<'_, OsStr>> {
+        log_option_error(self.option_values_os(name).map(Some)).unwrap_or_default()
+    }
+
+    fn option_values_os(&self, name: &str) -> Result<Vec<Cow<'_, OsStr>>, SpankError> {
+        let name: &str = &self.option_name(name);
+        if self.command_line_count(name) == 0 {
+            return Ok(self
+                .fallback_option_os(name)?
+                .map(|(value, _)| value)
+                .into_iter()
+                .collect());
+        }
+
+        match self.context() {
+            Ok(Context::JobScript) => Ok(self.command_line_value_os(name).into_iter().collect()),
+            _ => Ok(self
+                .opt_cache
+                .values
+                .get(name)
+                .into_iter()
+                .flatten()
+                .filter_map(|occurrence| occurrence.value_os().map(Cow::from))
+                .collect()),
+        }
+    }
+
+    /// Returns every occurrence of option `name`, with the value given and
+    /// where it was parsed
+    ///
+    /// Occurrences are returned in the order in which the option callbacks
+    /// were called, which allows to tell whether an option was set by the user
+    /// on the command line or forwarded to slurmstepd (see
+    /// [`OptionOccurrence::is_remote`]).
+    ///
+    /// *WARNING*: Slurm doesn't call option callbacks in the JobScript
+    /// context (prolog/epilog) so no occurrences are returned there. Use
+    /// get_option_value to retrieve option values in that context.
+    pub fn get_option_occurrences(&self, name: &str) -> &[OptionOccurrence] {
+        let name: &str = &self.option_name(name);
+        self.opt_cache
+            .values
+            .get(name)
+            .map_or(&[], |occurrences| occurrences.as_slice())
+    }
+
+    /// Returns the number of times option `name` was given
+    ///
+    /// Use this function to process flag options which may be repeated, such
+    /// as `--verbose --verbose`. If the option was not given on the command
+    /// line, 1 is returned if a value is resolved as with get_option_value_os.
     ///
     /// *WARNING*: If options have not yet been processed (e.g in init callbacks
-    /// or all slurmd contexts), this function will always return false.
-    pub fn is_option_set(&self, name: &str) -> bool {
+    /// or all slurmd contexts), only values from the plugin arguments or
+    /// default values are counted. Slurm only forwards the last occurrence of
+    /// each option to slurmstepd and to the prolog/epilog, so this function
+    /// returns at most 1 in the Remote and JobScript contexts.
+    pub fn option_count(&self, name: &str) -> usize {
+        let name: &str = &self.option_name(name);
+        match self.command_line_count(name) {
+            0 => log_option_error(self.fallback_option_os(name)).is_some() as usize,
+            count => count,
+        }
+    }
+
+    // Returns the number of times option `name`, already namespaced, was
+    // given on the command line
+    fn command_line_count(&self, name: &str) -> usize {
         match self.context() {
-            Ok(Context::JobScript) => self.getopt_os(name).is_ok(),
-            _ => self.opt_cache.values.contains_key(name),
+            Ok(Context::JobScript) => self.getopt_os(name).is_ok() as usize,
+            _ => self.opt_cache.values.get(name).map_or(0, Vec::len),
         }
     }
 
+    /// Returns whether an option was set
+    ///
+    /// Use this function to process flag options. Options which were not
+    /// given on the command line are set if a value is resolved as with
+    /// get_option_value_os.
+    ///
+    /// *WARNING*: If options have not yet been processed (e.g in init callbacks
+    /// or all slurmd contexts), only values from the plugin arguments or
+    /// default values are taken into account.
+    pub fn is_option_set(&self, name: &str) -> bool {
+        self.option_count(name) > 0
+    }
+
     spank_item_getter!(
         /// Returns the primary group id
         job_gid,
@@ -1020,47 +1583,153 @@ macro_rules! spank_log_user {
     })
 }
 
-// XXX: Slurm should only call us in a sequential and non-reentrant way but Rust
-// doesn't know that. The overhead of locking these Mutex at each Slurm callback
-// should be negligible and we'll get a clear error if something is called out
-// of order by mistake. However this is not ideal because it requires the Plugin
-// to be Send which can be restricting. We should probably confirm with Slurm
-// devs that all calls are sequential and switch to a static mut or similar.
-lazy_static! {
-    static ref OPTION_CACHE: Mutex<OptionCache> = Mutex::new(OptionCache::default());
-    static ref PLUGIN: Mutex<Option<Box<dyn Plugin>>> = Mutex::new(None);
+// Slurm calls plugins sequentially from a single thread in each process, so
+// the plugin state is kept in per-process storage without locking. This lets
+// plugins hold state which isn't Send. Re-entrant calls (e.g. a callback being
+// invoked while another one is still running) are detected and rejected with
+// an error rather than handing out a second mutable reference.
+struct ProcessLocal<T> {
+    in_use: AtomicBool,
+    value: UnsafeCell<Option<T>>,
 }
 
-#[doc(hidden)]
-pub fn spank_callback_with_globals<P: Plugin + Default + 'static, F>(func: F) -> c_int
+// SAFETY: Slurm never calls into a plugin concurrently and the in_use flag
+// guarantees that at most one reference to the value exists at any time.
+unsafe impl<T> Sync for ProcessLocal<T> {}
+
+struct ProcessLocalGuard<'a>(&'a AtomicBool);
+
+impl Drop for ProcessLocalGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl<T: Default> ProcessLocal<T> {
+    const fn new() -> Self {
+        ProcessLocal {
+            in_use: AtomicBool::new(false),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    // Runs `func` with exclusive access to the value, which is initialized on
+    // first use. Returns None if the value is already in use.
+    fn with<R>(&self, func: impl FnOnce(&mut T) -> R) -> Option<R> {
+        if self.in_use.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        // Release the value even if func panics
+        let _guard = ProcessLocalGuard(&self.in_use);
+
+        // SAFETY: the in_use flag ensures that this is the only reference
+        let value = unsafe { &mut *self.value.get() };
+        Some(func(value.get_or_insert_with(T::default)))
+    }
+}
+
+// The same shared object may be listed several times in plugstack.conf with
+// different arguments. Slurm then loads it only once but calls its callbacks
+// for each entry, so we keep a separate plugin instance for each set of
+// arguments. Callbacks don't tell which entry they are called for, so entries
+// with identical arguments share an instance.
+static INSTANCES: ProcessLocal<Vec<PluginInstance>> = ProcessLocal::new();
+
+struct PluginInstance {
+    argv: Vec<Vec<u8>>,
+    slot: PluginSlot,
+    opt_cache: OptionCache,
+}
+
+// Names of the options registered by all plugin instances
+static REGISTERED_OPTIONS: ProcessLocal<HashSet<String>> = ProcessLocal::new();
+
+// Option callbacks only receive the value registered with each option, so it
+// identifies both the plugin instance and the option within that instance.
+const OPTION_INDEX_BITS: u32 = 16;
+
+fn option_val(instance: usize, index: usize) -> Option<c_int> {
+    if index >= 1 << OPTION_INDEX_BITS {
+        return None;
+    }
+    let val = instance.checked_shl(OPTION_INDEX_BITS)? | index;
+    c_int::try_from(val).ok()
+}
+
+fn option_instance_index(val: c_int) -> (usize, usize) {
+    let val = val as usize;
+    (
+        val >> OPTION_INDEX_BITS,
+        val & ((1 << OPTION_INDEX_BITS) - 1),
+    )
+}
+
+#[derive(Default)]
+enum PluginSlot {
+    #[default]
+    Unloaded,
+    Loaded(Box<dyn Plugin>),
+    // The plugin could not be constructed. All callbacks fail.
+    Failed,
+}
+
+fn spank_callback_with_globals<F>(argc: c_int, argv: *const *const c_char, func: F) -> c_int
 where
-    F: FnOnce(&mut dyn Plugin, &mut OptionCache, bool) -> Result<(), Box<dyn Error>> + UnwindSafe,
+    F: FnOnce(&mut PluginSlot, &mut OptionCache) -> Result<(), Box<dyn Error>> + UnwindSafe,
 {
     let unwind_res = catch_unwind(|| {
-        // These Mutexes should never be contended unless something unreoverable
-        // happened before
-        let mut opt_cache = OPTION_CACHE
-            .try_lock()
-            .expect("Failed to acquire global options mutex");
-        let mut plugin_option = PLUGIN
-            .try_lock()
-            .expect("Failed to acquire global plugin mutex");
-
-        let mut need_setup = false;
-
-        let mut plugin = plugin_option.take().unwrap_or_else(|| {
-            let p = P::default();
-            need_setup = true;
-            Box::new(p)
-        });
+        let args: Vec<Vec<u8>> = unsafe { slice_from_raw_parts_or_empty(argv, argc as usize) }
+            .iter()
+            .map(|&arg| unsafe { CStr::from_ptr(arg) }.to_bytes().to_vec())
+            .collect();
+
+        let res = INSTANCES.with(|instances| {
+            let instance = match instances.iter().position(|i| i.argv == args) {
+                Some(pos) => &mut instances[pos],
+                None => {
+                    let opt_cache = OptionCache {
+                        instance: instances.len(),
+                        ..Default::default()
+                    };
+                    instances.push(PluginInstance {
+                        argv: args,
+                        slot: PluginSlot::Unloaded,
+                        opt_cache,
+                    });
+                    instances.last_mut().unwrap()
+                }
+            };
 
-        let err = match func(plugin.as_mut(), &mut opt_cache, need_setup) {
-            Ok(()) => 0,
-            Err(_) => -1,
-        };
-        plugin_option.replace(plugin);
+            STATIC_OPTION_VALUES.with(|values| {
+                for (name, value) in values.iter() {
+                    instance
+                        .opt_cache
+                        .values
+                        .insert(name.clone(), value.clone());
+                }
+            });
+
+            // The plugin is left unloaded if the callback panics
+            let mut slot = std::mem::take(&mut instance.slot);
 
-        err
+            let err = match func(&mut slot, &mut instance.opt_cache) {
+                Ok(()) => 0,
+                Err(_) => -1,
+            };
+            instance.slot = slot;
+
+            err
+        });
+
+        match res {
+            Some(err) => err,
+            None => {
+                spank_log_error!(
+                    "Internal spank-rs error: SPANK callback called while another one is running"
+                );
+                -1
+            }
+        }
     });
 
     match unwind_res {
@@ -1087,15 +1756,36 @@ where
 extern "C" fn spank_option_callback(
     val: std::os::raw::c_int,
     optarg: *const std::os::raw::c_char,
-    _remote: std::os::raw::c_int,
+    remote: std::os::raw::c_int,
 ) -> std::os::raw::c_int {
-    // This Mutex should never be contended unless something unrecoverable
-    // already happened before
-    let mut opt_cache = OPTION_CACHE
-        .try_lock()
-        .expect("Failed to acquire global options mutex");
+    INSTANCES
+        .with(|instances| {
+            let (instance, index) = option_instance_index(val);
+            match instances.get_mut(instance) {
+                Some(instance) => cache_option(
+                    &mut instance.opt_cache,
+                    index,
+                    OptionOccurrence::new(optarg, remote),
+                ),
+                None => {
+                    spank_log_error!(
+                        "Internal spank-rs error: received option callback {} for unknown plugin instance",
+                        val
+                    );
+                    -1
+                }
+            }
+        })
+        .unwrap_or_else(|| {
+            spank_log_error!(
+                "Internal spank-rs error: option callback called while a SPANK callback is running"
+            );
+            -1
+        })
+}
 
-    let name = opt_cache.options.get(val as usize).cloned();
+fn cache_option(opt_cache: &mut OptionCache, index: usize, occurrence: OptionOccurrence) -> c_int {
+    let name = opt_cache.options.get(index).cloned();
 
     let name = match name {
         None => {
@@ -1103,7 +1793,7 @@ extern "C" fn spank_option_callback(
                 LogLevel::Error,
                 &format!(
                     "Internal spank-rs error: received unexpected option callback {}",
-                    val
+                    index
                 ),
             );
             return -1;
@@ -1111,35 +1801,342 @@ extern "C" fn spank_option_callback(
         Some(name) => name,
     };
 
-    let optarg = {
-        if optarg.is_null() {
+    let name = match opt_cache.settings.get_mut(&name) {
+        Some(settings) => {
+            if let Some(msg) = &settings.deprecated {
+                // Only warn users once, from the command which they ran
+                let client = matches!(
+                    occurrence.context,
+                    Some(Context::Local | Context::Allocator)
+                );
+                if client && !occurrence.remote && !settings.deprecation_warned {
+                    slurm_spank_log(&format!(
+                        "Warning: option --{} is deprecated: {}",
+                        name, msg
+                    ));
+                    settings.deprecation_warned = true;
+                }
+            }
+            // Values of aliases are stored under the name of the option
+            settings.alias_of.clone().unwrap_or(name)
+        }
+        None => name,
+    };
+
+    // Reject invalid values right away so that srun, sbatch and salloc fail
+    // before the job is submitted
+    let settings = opt_cache.settings.get(&name);
+    if let (Some(settings), Some(value)) = (settings, &occurrence.value) {
+        if let Err(msg) = validate_option_value(settings, value) {
+            spank_log_error!("Invalid value for option --{}: {}", name, msg);
+            return -1;
+        }
+    }
+
+    let builtin = opt_cache
+        .settings
+        .get(&name)
+        .and_then(|settings| settings.builtin.clone());
+    match builtin {
+        Some(BuiltinOption::Debug { scope }) => {
+            let filter = occurrence
+                .value
+                .as_ref()
+                .map_or(Cow::from("debug"), |filter| filter.to_string_lossy());
+            if let Err(e) = set_tracing_filter(&scope, &filter) {
+                spank_log_error!("Invalid value for option --{}: {}", name, e);
+                return -1;
+            }
+        }
+        // Only display the help from the command line, not once the option
+        // is forwarded to slurmstepd. The option is then rejected so that the
+        // job doesn't run, rather than exiting here which would skip the
+        // cleanup of Slurm and of the other plugins.
+        Some(BuiltinOption::Help { plugin_name }) if !occurrence.remote => {
+            print_options_help(&plugin_name, opt_cache);
+            return -1;
+        }
+        _ => (),
+    }
+
+    opt_cache.values.entry(name).or_default().push(occurrence);
+    0
+}
+
+// Displays the options registered by a plugin along with their settings
+fn print_options_help(plugin_name: &str, opt_cache: &OptionCache) {
+    slurm_spank_log(&format!("Options provided by the {} plugin:", plugin_name));
+    for name in &opt_cache.options {
+        let Some(settings) = opt_cache.settings.get(name) else {
+            continue;
+        };
+        match &settings.arginfo {
+            Some(arginfo) => slurm_spank_log(&format!("  --{}={}", name, arginfo)),
+            None => slurm_spank_log(&format!("  --{}", name)),
+        }
+        if let Some(usage) = &settings.usage {
+            slurm_spank_log(&format!("      {}", usage));
+        }
+        if let Some(var) = &settings.env {
+            slurm_spank_log(&format!("      Environment variable: {}", var));
+        }
+        if let Some(key) = &settings.plugin_arg {
+            slurm_spank_log(&format!("      plugstack.conf argument: {}", key));
+        }
+        if let Some(default) = &settings.default {
+            slurm_spank_log(&format!("      Default: {}", default));
+        }
+    }
+}
+
+// Filter of the subscriber configured by Plugin::setup
+struct TracingFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    directives: String,
+}
+
+static TRACING_FILTER: OnceLock<TracingFilter> = OnceLock::new();
+
+// Directives added with the builtin debug option of each plugin or module,
+// by scope
+static DEBUG_DIRECTIVES: ProcessLocal<HashMap<String, String>> = ProcessLocal::new();
+
+// Sets the filter directives of the events within spans matching `scope`
+fn set_tracing_filter(scope: &str, filter: &str) -> Result<(), String> {
+    let Some(tracing_filter) = TRACING_FILTER.get() else {
+        return Err("the tracing filter was not set up by the plugin".to_string());
+    };
+    let scoped = scope_directives(scope, filter)?;
+
+    DEBUG_DIRECTIVES
+        .with(|directives| {
+            directives.insert(scope.to_string(), scoped);
+            let all = std::iter::once(&tracing_filter.directives)
+                .chain(directives.values())
+                .filter(|d| !d.is_empty())
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(",");
+            let filter = EnvFilter::try_new(all).map_err(|e| e.to_string())?;
+            tracing_filter
+                .handle
+                .reload(filter)
+                .map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|| Err("re-entrant tracing filter update".to_string()))
+}
+
+// Restricts each directive of `filter` to the spans matching `scope`
+fn scope_directives(scope: &str, filter: &str) -> Result<String, String> {
+    filter
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| {
+            if directive.contains('[') {
+                return Err(format!("span filters are not supported: {}", directive));
+            }
+            Ok(match directive.split_once('=') {
+                Some((target, level)) => format!("{}[{}]={}", target, scope, level),
+                None if directive.parse::<LevelFilter>().is_ok() => {
+                    format!("[{}]={}", scope, directive)
+                }
+                None => format!("{}[{}]", directive, scope),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|directives| directives.join(","))
+}
+
+/// Occurrence of a plugin option, as received by the option callback
+#[derive(Debug, Clone)]
+pub struct OptionOccurrence {
+    value: Option<OsString>,
+    remote: bool,
+    context: Option<Context>,
+}
+
+impl OptionOccurrence {
+    fn new(optarg: *const c_char, remote: c_int) -> Self {
+        let value = if optarg.is_null() {
             None
         } else {
-            Some(
-                std::ffi::OsStr::from_bytes(unsafe { std::ffi::CStr::from_ptr(optarg) }.to_bytes())
-                    .to_os_string(),
-            )
+            Some(OsStr::from_bytes(unsafe { CStr::from_ptr(optarg) }.to_bytes()).to_os_string())
+        };
+        let context = Context::try_from(unsafe { spank_sys::spank_context() }).ok();
+
+        OptionOccurrence {
+            value,
+            remote: remote != 0,
+            context,
         }
+    }
+
+    /// Returns the value given to the option as an OsStr, or None for flags
+    pub fn value_os(&self) -> Option<&OsStr> {
+        self.value.as_deref()
+    }
+
+    /// Returns the value given to the option as a str, or None for flags
+    ///
+    /// An error is returned if the value is not valid UTF-8.
+    pub fn value(&self) -> Result<Option<&str>, SpankError> {
+        match &self.value {
+            Some(value) => Ok(Some(
+                value
+                    .to_str()
+                    .ok_or_else(|| SpankError::from_os_str(value))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whether the option was parsed remotely, i.e. forwarded to
+    /// slurmstepd, rather than from the srun, sbatch or salloc command line
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    /// Returns the context in which the option was parsed
+    pub fn context(&self) -> Option<Context> {
+        self.context
+    }
+}
+
+// Values of the options declared in the static spank_options table. These
+// options belong to the shared object rather than to a plugstack entry, so
+// their values are merged into the options of every plugin instance.
+static STATIC_OPTION_VALUES: ProcessLocal<HashMap<String, Vec<OptionOccurrence>>> =
+    ProcessLocal::new();
+
+#[doc(hidden)]
+// Declaration of an option of the static spank_options table. All strings are
+// NUL-terminated, empty strings stand for missing values. This struct is only
+// public so that it may be used by the plugin export macros.
+#[derive(Clone, Copy)]
+pub struct StaticOptionSpec {
+    pub name: &'static str,
+    pub arg: &'static str,
+    pub usage: &'static str,
+}
+
+impl StaticOptionSpec {
+    pub const NONE: StaticOptionSpec = StaticOptionSpec {
+        name: "",
+        arg: "",
+        usage: "",
     };
+}
 
-    opt_cache.values.insert(name, optarg);
-    0
+#[doc(hidden)]
+#[repr(transparent)]
+// NULL-terminated option table exported as the spank_options symbol. This
+// struct is only public so that it may be used by the plugin export macros.
+pub struct StaticSpankOptions<const N: usize>(pub [spank_sys::spank_option; N]);
+
+// SAFETY: the table only holds pointers to static strings and is never
+// modified.
+unsafe impl<const N: usize> Sync for StaticSpankOptions<N> {}
+
+const fn static_str_or_null(s: &'static str) -> *const c_char {
+    if s.is_empty() {
+        ptr::null()
+    } else {
+        s.as_ptr() as *const c_char
+    }
+}
+
+impl<const N: usize> StaticSpankOptions<N> {
+    pub const fn new(specs: &[StaticOptionSpec], cb: spank_sys::spank_opt_cb_f) -> Self {
+        assert!(
+            specs.len() + 1 == N,
+            "static option table size doesn't match the number of options"
+        );
+
+        let mut table = [spank_sys::spank_option {
+            name: ptr::null(),
+            arginfo: ptr::null(),
+            usage: ptr::null(),
+            has_arg: 0,
+            val: 0,
+            cb: None,
+        }; N];
+
+        let mut i = 0;
+        while i < specs.len() {
+            let spec = specs[i];
+            assert!(
+                !spec.name.is_empty(),
+                "static SPANK options must have a name"
+            );
+            table[i] = spank_sys::spank_option {
+                name: static_str_or_null(spec.name),
+                arginfo: static_str_or_null(spec.arg),
+                usage: static_str_or_null(spec.usage),
+                has_arg: !spec.arg.is_empty() as c_int,
+                val: i as c_int,
+                cb,
+            };
+            i += 1;
+        }
+        StaticSpankOptions(table)
+    }
+}
+
+#[doc(hidden)]
+// This function is only public so that it may be called from the option
+// callback generated by the macros. It stores the value of an option of the
+// static spank_options table.
+pub fn static_option_callback(
+    options: &[spank_sys::spank_option],
+    val: c_int,
+    optarg: *const c_char,
+    remote: c_int,
+) -> c_int {
+    let name = match options.get(val as usize) {
+        Some(option) if !option.name.is_null() => unsafe { CStr::from_ptr(option.name) },
+        _ => {
+            spank_log_error!(
+                "Internal spank-rs error: received unexpected static option callback {}",
+                val
+            );
+            return -1;
+        }
+    };
+    let name = name.to_string_lossy().into_owned();
+    let occurrence = OptionOccurrence::new(optarg, remote);
+
+    STATIC_OPTION_VALUES
+        .with(|values| {
+            values.entry(name).or_default().push(occurrence);
+            0
+        })
+        .unwrap_or_else(|| {
+            spank_log_error!(
+                "Internal spank-rs error: option callback called while another one is running"
+            );
+            -1
+        })
 }
 
 #[doc(hidden)]
 // This function only public so that it may be called from the callbacks
 // generated by the macro. It should not be called to create handles manually.
-pub fn init_spank_handle(
+pub fn init_spank_handle<'a>(
     spank: spank_sys::spank_t,
+    plugin_name: &'a str,
     argc: c_int,
     argv: *const *const c_char,
-    opt_cache: &mut OptionCache,
-) -> SpankHandle<'_> {
+    opt_cache: &'a mut OptionCache,
+) -> SpankHandle<'a> {
     SpankHandle {
         spank,
+        plugin_name,
         argc,
         argv,
         opt_cache,
+        option_prefix: None,
+        module: None,
     }
 }
 
@@ -1154,14 +2151,272 @@ pub fn make_cb_span(id: &str, cb: &str, ctx: &str, task_id: Option<u32>) -> trac
     }
 }
 
+#[doc(hidden)]
+pub type PluginCallback = fn(&mut dyn Plugin, &mut SpankHandle) -> Result<(), Box<dyn Error>>;
+
+#[doc(hidden)]
+pub type PluginConstructor = fn(&mut SpankHandle) -> Result<Box<dyn Plugin>, Box<dyn Error>>;
+
+#[doc(hidden)]
+// This function is only public so that it may be called from the callbacks
+// generated by the macros. It runs a single Plugin callback within the global
+// plugin state, setting up the plugin first if needed.
+pub fn spank_hook(
+    plugin_name: &'static [u8],
+    new_plugin: PluginConstructor,
+    cb_name: &'static str,
+    spank: spank_sys::spank_t,
+    ac: c_int,
+    argv: *const *const c_char,
+    cb: PluginCallback,
+) -> c_int {
+    spank_callback_with_globals(ac, argv, |slot, options| {
+        let plugin_name = CStr::from_bytes_with_nul(plugin_name)?.to_str()?;
+        let mut spank = init_spank_handle(spank, plugin_name, ac, argv, options);
+
+        if let PluginSlot::Unloaded = slot {
+            match new_plugin(&mut spank) {
+                Ok(mut plugin) => {
+                    let res = plugin.setup(&mut spank);
+                    if let Err(e) = &res {
+                        plugin.report_error(&mut spank, e.as_ref());
+                    }
+                    *slot = PluginSlot::Loaded(plugin);
+                    res?;
+                }
+                Err(e) => {
+                    // There is no plugin to report the error, and no tracing
+                    // subscriber either, so log it directly
+                    spank_log_error!("{}", error_report(e.as_ref()));
+                    *slot = PluginSlot::Failed;
+                    return Err(e);
+                }
+            }
+        }
+
+        let plugin = match slot {
+            PluginSlot::Loaded(plugin) => plugin,
+            // The plugin could not be constructed. Fail every callback so that
+            // a required plugin keeps failing the job.
+            _ => return Err(format!("plugin {} failed to initialize", plugin_name).into()),
+        };
+
+        let context = spank
+            .context()
+            .map(|ctx| format!("{:?}", ctx))
+            .unwrap_or("Error".to_string());
+
+        let tid = spank.task_global_id().ok();
+
+        let span = make_cb_span(plugin_name, cb_name, &context, tid);
+        let _guard = span.enter();
+
+        let res = cb(plugin.as_mut(), &mut spank);
+        if let Err(e) = &res {
+            plugin.report_error(&mut spank, e.as_ref());
+        }
+
+        // Slurm disables options which clash with those of other plugins
+        // without returning an error, so list ours to help diagnose clashes
+        if cb_name == "slurm_spank_init" {
+            let options: Vec<_> = spank
+                .registered_options()
+                .map(|name| format!("--{}", name))
+                .collect();
+            if !options.is_empty() {
+                debug!("Registered options: {}", options.join(", "));
+            }
+        }
+        res
+    })
+}
+
+#[doc(hidden)]
+#[macro_export]
+// Defines the C callbacks of the SPANK API for a plugin type. This macro is
+// only public so that it may be used by SPANK_PLUGIN! and #[plugin]. It
+// expects a `plugin_name` static holding the NUL-terminated plugin name to be
+// in scope.
+macro_rules! __spank_plugin_hooks {
+    (@new $spank:ident => $new_plugin:expr) => {
+        // Items are defined in an anonymous const so that they cannot clash
+        // with the plugin's own items
+        const _: () = {
+            // The plugin is only constructed here so that a type which doesn't
+            // implement the required traits is reported once
+            #[allow(unused_variables)]
+            fn new_plugin(
+                $spank: &mut $crate::SpankHandle,
+            ) -> std::result::Result<
+                std::boxed::Box<dyn $crate::Plugin>,
+                std::boxed::Box<dyn std::error::Error>,
+            > {
+                Ok(std::boxed::Box::new($new_plugin))
+            }
+
+            $crate::__spank_plugin_hooks!(
+                @hooks
+                slurm_spank_init => init,
+                slurm_spank_job_prolog => job_prolog,
+                slurm_spank_init_post_opt => init_post_opt,
+                slurm_spank_local_user_init => local_user_init,
+                slurm_spank_user_init => user_init,
+                slurm_spank_task_init_privileged => task_init_privileged,
+                slurm_spank_task_init => task_init,
+                slurm_spank_task_post_fork => task_post_fork,
+                slurm_spank_task_exit => task_exit,
+                slurm_spank_job_epilog => job_epilog,
+                slurm_spank_slurmd_exit => slurmd_exit,
+                slurm_spank_exit => exit
+            );
+        };
+    };
+    (@hooks $($c_spank_cb:ident => $rust_spank_cb:ident),*) => {
+        $(
+            #[no_mangle]
+            #[doc(hidden)]
+            pub extern "C" fn $c_spank_cb(
+                spank: $crate::spank_sys::spank_t,
+                ac: std::os::raw::c_int,
+                argv: *const *const std::os::raw::c_char,
+            ) -> std::os::raw::c_int {
+                $crate::spank_hook(
+                    &plugin_name,
+                    new_plugin,
+                    stringify!($c_spank_cb),
+                    spank,
+                    ac,
+                    argv,
+                    |plugin, spank| plugin.$rust_spank_cb(spank),
+                )
+            }
+        )*
+    };
+    ($spank_ty:ty) => {
+        $crate::__spank_plugin_hooks!(
+            @new spank => <$spank_ty as std::default::Default>::default()
+        );
+    };
+    ($spank_ty:ty, $constructor:expr) => {
+        $crate::__spank_plugin_hooks!(
+            @new spank => {
+                let plugin: $spank_ty = $constructor(spank)?;
+                plugin
+            }
+        );
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+// Defines the symbols identifying a SPANK plugin. This macro is only public so
+// that it may be used by the plugin export macros.
+macro_rules! __spank_plugin_symbols {
+    ($spank_name:literal, $spank_version:expr) => {
+        #[no_mangle]
+        pub static plugin_name: [u8; $spank_name.len() + 1] =
+            *$crate::byte_strings::concat_bytes!($spank_name, "\0");
+        #[no_mangle]
+        pub static mut plugin_type: [u8; 6] = *b"spank\0";
+        #[no_mangle]
+        pub static plugin_version: std::os::raw::c_uint = $spank_version;
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+// Defines the static spank_options table of a plugin. This macro is only
+// public so that it may be used by the plugin export macros.
+macro_rules! __spank_static_options {
+    ($($option:tt),* $(,)?) => {
+        const _: () = {
+            #[allow(clippy::needless_update)]
+            const SPECS: &[$crate::StaticOptionSpec] =
+                &[$($crate::__spank_static_options!(@spec $option)),*];
+
+            #[no_mangle]
+            pub static spank_options: $crate::StaticSpankOptions<{ SPECS.len() + 1 }> =
+                $crate::StaticSpankOptions::new(SPECS, Some(static_option_callback));
+
+            extern "C" fn static_option_callback(
+                val: std::os::raw::c_int,
+                optarg: *const std::os::raw::c_char,
+                remote: std::os::raw::c_int,
+            ) -> std::os::raw::c_int {
+                $crate::static_option_callback(&spank_options.0, val, optarg, remote)
+            }
+        };
+    };
+    (@spec { $($field:ident : $value:literal),* $(,)? }) => {
+        $crate::StaticOptionSpec {
+            $($field: concat!($value, "\0"),)*
+            ..$crate::StaticOptionSpec::NONE
+        }
+    };
+}
+
 pub use spank_sys::SLURM_VERSION_NUMBER;
 
+/// Export a Plugin to make it available to the Slurm plugin loader
+///
+/// This attribute is an alternative to the [`SPANK_PLUGIN!`] macro. It can be
+/// applied either to the plugin struct or to its `impl Plugin` block:
+///
+///```rust,no_run
+/// use slurm_spank::{Plugin, SpankHandle};
+/// use std::error::Error;
+///
+/// #[slurm_spank::plugin(name = "renice")]
+/// #[derive(Default)]
+/// struct SpankRenice {
+///     prio: Option<i32>,
+/// }
+///
+/// unsafe impl Plugin for SpankRenice {
+///     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         Ok(())
+///     }
+/// }
+///```
+///
+/// The `name` argument is the name of the SPANK plugin. It is checked at
+/// compile time: it must not be empty, contain NUL characters or be longer
+/// than 64 bytes.
+///
+/// The optional `version` argument is the Slurm version for which the plugin
+/// is built. It defaults to [`SLURM_VERSION_NUMBER`].
+///
+/// The plugin type must implement [`Plugin`] and [`Default`], unless a
+/// `constructor` argument is given, such as `constructor = SpankRenice::new`.
+/// The constructor is used as described in [`SPANK_PLUGIN!`]. Unlike
+/// [`SPANK_PLUGIN!`], no helper items are added to the calling module.
+///
+/// Options of the static `spank_options` table (see [`SPANK_PLUGIN!`]) are
+/// declared with repeated `option` arguments:
+///
+///```rust,no_run
+/// # use slurm_spank::Plugin;
+/// #[slurm_spank::plugin(
+///     name = "renice",
+///     option(name = "renice", arg = "prio", usage = "Re-nice job tasks to priority [prio]"),
+///     option(name = "renice-verbose", usage = "Report the new priority"),
+/// )]
+/// #[derive(Default)]
+/// struct SpankRenice {}
+/// # unsafe impl Plugin for SpankRenice {}
+///```
+pub use slurm_spank_macros::plugin;
+
 #[macro_export]
 /// Export a Plugin to make it available to the Slurm plugin loader
 ///
 /// # Example
 ///
 ///```rust,no_run
+/// # use slurm_spank::{Plugin, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// # #[derive(Default)]
+/// # struct SpankRenice {}
+/// # unsafe impl Plugin for SpankRenice {}
 ///SPANK_PLUGIN!(b"renice", SLURM_VERSION_NUMBER, SpankRenice);
 ///```
 ///
@@ -1170,93 +2425,143 @@ pub use spank_sys::SLURM_VERSION_NUMBER;
 /// The second argument is the Slurm version for which the plugin is built, specified in hexadecimal (2 digits per version component).
 /// The SLURM_VERSION_NUMBER constant can be used. It refers to the version of the Slurm headers that the plugin is built against.
 ///
-/// The last argument is a struct for which the Plugin trait has been implemented
+/// The third argument is a struct for which the Plugin trait has been
+/// implemented. By default, the plugin is created with [`Default::default`]
+/// before the first callback from SPANK.
+///
+/// An optional fourth argument provides a constructor to use instead. It is
+/// called with a [`SpankHandle`] so that the plugin can be built from its
+/// configuration, e.g. from [`SpankHandle::plugin_argv`]. If the constructor
+/// returns an error, the error is logged and the callback fails, as do all
+/// further callbacks of the plugin. As there is no plugin instance yet, the
+/// error is logged as by the default implementation of
+/// [`Plugin::report_error`].
+///
+///```rust,no_run
+/// # use slurm_spank::{Plugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// # use std::error::Error;
+/// struct SpankRenice {
+///     min_prio: i32,
+/// }
+///
+/// impl SpankRenice {
+///     fn new(spank: &mut SpankHandle) -> Result<Self, Box<dyn Error>> {
+///         let mut min_prio = -20;
+///         for arg in spank.plugin_argv()? {
+///             if let Some(value) = arg.strip_prefix("min_prio=") {
+///                 min_prio = value.parse()?;
+///             }
+///         }
+///         Ok(SpankRenice { min_prio })
+///     }
+/// }
+/// # unsafe impl Plugin for SpankRenice {}
+///
+///SPANK_PLUGIN!(b"renice", SLURM_VERSION_NUMBER, SpankRenice, SpankRenice::new);
+///```
+///
+/// Options can also be declared in a trailing `options` argument, after the
+/// plugin type or the constructor. They are exported in the static
+/// `spank_options` table which Slurm reads when loading the plugin, which is
+/// useful in contexts where options registered with
+/// [`SpankHandle::register_option`] are not taken into account. Each option
+/// has a `name` and optional `usage` and `arg` fields, the latter making the
+/// option take a value. Their values are retrieved like those of other
+/// options, e.g. with [`SpankHandle::get_option_value`].
+///
+///```rust,no_run
+/// # use slurm_spank::{Plugin, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// # #[derive(Default)]
+/// # struct SpankRenice {}
+/// # unsafe impl Plugin for SpankRenice {}
+///SPANK_PLUGIN!(b"renice", SLURM_VERSION_NUMBER, SpankRenice, options = [
+///    { name: "renice", arg: "prio", usage: "Re-nice job tasks to priority [prio]" },
+///    { name: "renice-verbose", usage: "Report the new priority" },
+///]);
+///```
+///
+/// Static options belong to the shared object rather than to a `plugstack.conf`
+/// entry: they are not namespaced by [`PluginStack`] and their values are
+/// visible from every plugin instance.
+///
+/// See also the [`plugin`] attribute which checks the plugin name at compile
+/// time.
 macro_rules! SPANK_PLUGIN {
     ($spank_name:literal, $spank_version:expr, $spank_ty:ty) => {
-        const fn byte_string_size<T>(_: &T) -> usize {
-            std::mem::size_of::<T>()
-        }
-        #[no_mangle]
-        pub static plugin_name: [u8; byte_string_size($spank_name) + 1] =
-            *$crate::byte_strings::concat_bytes!($spank_name, "\0");
-        #[no_mangle]
-        pub static mut plugin_type: [u8; 6] = *b"spank\0";
-        #[no_mangle]
-        pub static plugin_version: std::os::raw::c_uint = $spank_version;
-
-        fn _check_spank_trait<T: Plugin>() {}
-        fn _t() {
-            _check_spank_trait::<$spank_ty>()
-        }
-
-        macro_rules! spank_hook {
-            ($c_spank_cb:ident, $rust_spank_cb:ident) => {
-                #[no_mangle]
-                #[doc(hidden)]
-                pub extern "C" fn $c_spank_cb(
-                    spank: $crate::spank_sys::spank_t,
-                    ac: std::os::raw::c_int,
-                    argv: *const *const std::os::raw::c_char,
-                ) -> std::os::raw::c_int {
-                    $crate::spank_callback_with_globals::<$spank_ty, _>(
-                        |plugin, options, need_setup| {
-                            let mut spank = $crate::init_spank_handle(spank, ac, argv, options);
-
-                            if need_setup {
-                                plugin.setup(&mut spank).map_err(|e| {
-                                    plugin.report_error(&mut spank, e.as_ref());
-                                    e
-                                })?;
-                            }
-
-                            let context = spank
-                                .context()
-                                .map(|ctx| format!("{:?}", ctx))
-                                .unwrap_or("Error".to_string());
-
-                            let tid = spank.task_global_id().ok();
-
-                            let span = $crate::make_cb_span(
-                                std::ffi::CStr::from_bytes_with_nul(&plugin_name)?.to_str()?,
-                                stringify!($c_spank_cb),
-                                &context,
-                                tid,
-                            );
-                            let _guard = span.enter();
-
-                            unsafe {
-                                plugin.$rust_spank_cb(&mut spank).map_err(|e| {
-                                    plugin.report_error(&mut spank, e.as_ref());
-                                    e
-                                })
-                            }
-                        },
-                    )
-                }
-            };
-        }
+        $crate::__spank_plugin_symbols!($spank_name, $spank_version);
+        $crate::__spank_plugin_hooks!($spank_ty);
+    };
+    ($spank_name:literal, $spank_version:expr, $spank_ty:ty, options = [$($options:tt)*]) => {
+        $crate::SPANK_PLUGIN!($spank_name, $spank_version, $spank_ty);
+        $crate::__spank_static_options!($($options)*);
+    };
+    (
+        $spank_name:literal,
+        $spank_version:expr,
+        $spank_ty:ty,
+        $constructor:expr,
+        options = [$($options:tt)*]
+    ) => {
+        $crate::SPANK_PLUGIN!($spank_name, $spank_version, $spank_ty, $constructor);
+        $crate::__spank_static_options!($($options)*);
+    };
+    ($spank_name:literal, $spank_version:expr, $spank_ty:ty, $constructor:expr) => {
+        $crate::__spank_plugin_symbols!($spank_name, $spank_version);
+        $crate::__spank_plugin_hooks!($spank_ty, $constructor);
+    };
+}
 
-        spank_hook!(slurm_spank_init, init);
-        spank_hook!(slurm_spank_job_prolog, job_prolog);
-        spank_hook!(slurm_spank_init_post_opt, init_post_opt);
-        spank_hook!(slurm_spank_local_user_init, local_user_init);
-        spank_hook!(slurm_spank_user_init, user_init);
-        spank_hook!(slurm_spank_task_init_privileged, task_init_privileged);
-        spank_hook!(slurm_spank_task_init, task_init);
-        spank_hook!(slurm_spank_task_post_fork, task_post_fork);
-        spank_hook!(slurm_spank_task_exit, task_exit);
-        spank_hook!(slurm_spank_job_epilog, job_epilog);
-        spank_hook!(slurm_spank_slurmd_exit, slurmd_exit);
-        spank_hook!(slurm_spank_exit, exit);
+#[macro_export]
+/// Export a plugin defined with a [`SpankPluginBuilder`] to make it available
+/// to the Slurm plugin loader
+///
+/// # Example
+///
+///```rust,no_run
+/// use slurm_spank::{spank_log_user, SpankPluginBuilder, SLURM_VERSION_NUMBER, SPANK_PLUGIN_BUILDER};
+///
+/// SPANK_PLUGIN_BUILDER!(b"hello", SLURM_VERSION_NUMBER, hello);
+///
+/// fn hello() -> SpankPluginBuilder {
+///     SpankPluginBuilder::new().on_user_init(|_, _| {
+///         spank_log_user!("Hello!");
+///         Ok(())
+///     })
+/// }
+///```
+///
+/// The first two arguments are the same as for [`SPANK_PLUGIN!`]: the plugin is
+/// named after the first one, which [`SpankHandle::plugin_name`] returns.
+///
+/// The last argument is a function returning the [`SpankPluginBuilder`]. It is
+/// called before the first callback from SPANK.
+macro_rules! SPANK_PLUGIN_BUILDER {
+    ($spank_name:literal, $spank_version:expr, $builder:expr) => {
+        $crate::__spank_plugin_symbols!($spank_name, $spank_version);
+        $crate::__spank_plugin_hooks!(@new spank => $builder());
     };
 }
 
 /// Implement this trait to create a SPANK plugin
+///
+/// Slurm runs the callbacks of a plugin sequentially, so plugins don't need to
+/// be `Send` and may hold types such as `Rc` or `RefCell`.
+///
+/// If the plugin is listed several times in `plugstack.conf` with different
+/// arguments, a separate instance is created for each entry, with its own
+/// options. As option names are shared by all plugins, each instance must
+/// register distinct option names, e.g. derived from its arguments.
+///
+/// Slurm doesn't tell plugins their position in `plugstack.conf`, so
+/// instances are identified by their arguments only: entries with identical
+/// arguments share the same instance, whose callbacks are then called once
+/// for each entry. Give each entry distinct arguments to keep them apart.
+///
 /// # Safety
 /// The task callbacks (task_init, task_init_privileged, ...) are called from child processes which slurmstepd creates by forking itself.
 /// This may lead to deadlocks or other issues if the Rust plugin is multi-threaded (see <https://man7.org/linux/man-pages/man7/signal-safety.7.html>)
 #[allow(unused_variables)]
-pub unsafe trait Plugin: Send {
+pub unsafe trait Plugin {
     /// Called just after plugins are loaded.
     ///
     /// In remote context, this is just after job step is initialized. This
@@ -1352,14 +2657,7 @@ pub unsafe trait Plugin: Send {
     /// The default implementation logs errors through SPANK along with their
     /// causes.
     fn report_error(&self, spank: &mut SpankHandle, error: &dyn Error) {
-        // TODO: use error iterators once they're stable
-        let mut report = error.to_string();
-        let mut error = error;
-        while let Some(source) = error.source() {
-            report.push_str(&format!(": {}", source));
-            error = source;
-        }
-        error!("{}", &report);
+        error!("{}", error_report(error));
     }
 
     /// Called before the first callback from SPANK
@@ -1370,20 +2668,48 @@ pub unsafe trait Plugin: Send {
             Context::Local | Context::Allocator => "error",
             _ => "debug",
         };
-        let filter_layer =
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
+        let directives = std::env::var(EnvFilter::DEFAULT_ENV)
+            .ok()
+            .filter(|directives| EnvFilter::try_new(directives).is_ok())
+            .unwrap_or_else(|| default_level.to_string());
+        let filter_layer = EnvFilter::new(&directives);
+        // The filter may be changed with the builtin debug option
+        let (filter_layer, filter_handle) = reload::Layer::new(filter_layer);
         let fmt_layer = layer()
             .with_ansi(false)
             .event_format(SpankTraceFormatter {})
             .with_writer(SpankTraceWriter {});
-        Registry::default()
+        match Registry::default()
             .with(filter_layer)
             .with(fmt_layer)
-            .init();
+            .try_init()
+        {
+            Ok(()) => {
+                let _ = TRACING_FILTER.set(TracingFilter {
+                    handle: filter_handle,
+                    directives,
+                });
+            }
+            // Another instance of the plugin already set up a subscriber
+            Err(_) if TRACING_FILTER.get().is_some() => (),
+            Err(e) => spank_log_error!("Failed to set up tracing subscriber: {}", e),
+        }
         Ok(())
     }
 }
 
+// Formats an error along with its causes
+fn error_report(error: &dyn Error) -> String {
+    // TODO: use error iterators once they're stable
+    let mut report = error.to_string();
+    let mut error = error;
+    while let Some(source) = error.source() {
+        report.push_str(&format!(": {}", source));
+        error = source;
+    }
+    report
+}
+
 struct SpankTraceFormatter;
 
 impl<S, N> FormatEvent<S, N> for SpankTraceFormatter
@@ -1525,18 +2851,43 @@ impl fmt::Display for SpankApiError {
     }
 }
 
+// Slurm provides spank_strerror to the plugins it loads. Unit tests run
+// outside of Slurm and are linked against this stub instead.
+#[cfg(test)]
+#[no_mangle]
+extern "C" fn spank_strerror(_err: spank_sys::spank_err_t) -> *const c_char {
+    static MESSAGE: &[u8] = b"SPANK error\0";
+    MESSAGE.as_ptr().cast()
+}
+
 impl Error for SpankError {}
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 /// Main Error enum for interfaces provided by this crate
 pub enum SpankError {
     CStringError(String),
+    OptionExists { name: String },
+    InvalidOptionValue(String, String),
     EnvExists(String),
     IdNotFound(u32),
     PidNotFound(pid_t),
     SpankAPI(String, SpankApiError),
     Utf8Error(String),
     Overflow(usize),
+    ForeignPluginLoad(String, String),
+    ForeignPluginCallback(String, c_int),
+    StateEncoding(String, String),
+    StateTooLarge(String, usize),
+    EnvProfile(String, String),
+    EnvApply(EnvApplyError),
+    InvalidPattern(String, String),
+    InvalidPluginArg(String, String),
+    SecretFile(String, String),
+    TaskWrapper(String, String),
+    JobCredentials(String),
+    CommandDenied(String),
+    Mount(String, String),
 }
 
 impl SpankError {
@@ -1591,6 +2942,47 @@ impl fmt::Display for SpankError {
             SpankError::PidNotFound(p) => write!(f, "Could not find pid {}", p),
             SpankError::IdNotFound(i) => write!(f, "Could not find id {}", i),
             SpankError::Overflow(u) => write!(f, "Integer overflow: {}", u),
+            SpankError::OptionExists { name } => {
+                write!(f, "Option --{} is already registered", name)
+            }
+            SpankError::InvalidOptionValue(name, e) => {
+                write!(f, "Invalid value for option --{}: {}", name, e)
+            }
+            SpankError::ForeignPluginLoad(path, e) => {
+                write!(f, "Failed to load SPANK plugin {}: {}", path, e)
+            }
+            SpankError::ForeignPluginCallback(cb, rc) => {
+                write!(f, "SPANK plugin callback {} returned {}", cb, rc)
+            }
+            SpankError::StateEncoding(key, e) => {
+                write!(f, "Cannot encode or decode state {}: {}", key, e)
+            }
+            SpankError::StateTooLarge(key, size) => {
+                write!(f, "State {} is too large ({} bytes)", key, size)
+            }
+            SpankError::EnvProfile(name, e) => {
+                write!(f, "Cannot load environment profile {}: {}", name, e)
+            }
+            SpankError::EnvApply(e) => write!(f, "Cannot apply environment changes: {}", e),
+            SpankError::InvalidPattern(pattern, e) => {
+                write!(f, "Invalid pattern {}: {}", pattern, e)
+            }
+            SpankError::InvalidPluginArg(arg, e) => {
+                write!(f, "Invalid plugin argument {}: {}", arg, e)
+            }
+            SpankError::SecretFile(path, e) => {
+                write!(f, "Cannot use secret file {}: {}", path, e)
+            }
+            SpankError::TaskWrapper(program, e) => {
+                write!(f, "Cannot wrap task with {}: {}", program, e)
+            }
+            SpankError::JobCredentials(e) => {
+                write!(f, "Cannot switch to the credentials of the job user: {}", e)
+            }
+            SpankError::CommandDenied(command) => {
+                write!(f, "Command {} is not allowed", command)
+            }
+            SpankError::Mount(operation, e) => write!(f, "Cannot {}: {}", operation, e),
         }
     }
 }
@@ -1613,6 +3005,11 @@ pub struct SpankOption {
     name: String,
     arginfo: Option<String>,
     usage: Option<String>,
+    optional_value: bool,
+    aliases: Vec<OptionAlias>,
+    context_usage: Vec<(Context, String)>,
+    contexts: Option<Vec<Context>>,
+    settings: OptionSettings,
 }
 
 impl SpankOption {
@@ -1621,16 +3018,139 @@ impl SpankOption {
             name: name.to_string(),
             arginfo: None,
             usage: None,
+            optional_value: false,
+            aliases: Vec::new(),
+            context_usage: Vec::new(),
+            contexts: None,
+            settings: OptionSettings::default(),
         }
     }
     pub fn usage(mut self, usage: &str) -> Self {
         self.usage = Some(usage.to_string());
         self
     }
+    /// Sets the usage text displayed in `context` instead of the one set
+    /// with usage()
+    ///
+    /// This allows to describe the option differently for srun
+    /// ([`Context::Local`]) and for sbatch and salloc ([`Context::Allocator`]).
+    pub fn usage_for(mut self, context: Context, usage: &str) -> Self {
+        self.context_usage.push((context, usage.to_string()));
+        self
+    }
+    /// Restricts the option to the commands corresponding to `contexts`
+    ///
+    /// Use [`Context::Local`] for srun and [`Context::Allocator`] for sbatch
+    /// and salloc. Other contexts are ignored: the option is always registered
+    /// in the remote, job script and slurmd contexts so that its value
+    /// remains available to the plugin.
+    ///
+    ///```rust,no_run
+    /// # use slurm_spank::{Context, SpankOption};
+    /// // Only available with srun
+    /// SpankOption::new("greet")
+    ///     .takes_value("name")
+    ///     .contexts(&[Context::Local]);
+    ///```
+    pub fn contexts(mut self, contexts: &[Context]) -> Self {
+        self.contexts = Some(contexts.to_vec());
+        self
+    }
     pub fn takes_value(mut self, arg_name: &str) -> Self {
         self.arginfo = Some(arg_name.to_string());
         self
     }
+    /// Adds an alternative name for the option
+    ///
+    /// The alias is registered along with the option and takes the same
+    /// value. Its values are merged with those of the option, which is
+    /// always queried by its own name.
+    ///
+    ///```rust,no_run
+    /// # use slurm_spank::SpankOption;
+    /// SpankOption::new("renice-prio")
+    ///     .takes_value("prio")
+    ///     .alias("renice")
+    ///     .deprecated("use --renice-prio");
+    ///```
+    pub fn alias(mut self, name: &str) -> Self {
+        self.aliases.push(OptionAlias {
+            name: name.to_string(),
+            deprecated: None,
+        });
+        self
+    }
+    /// Marks the last alias added with alias() as deprecated, or the option
+    /// itself if it has no aliases
+    ///
+    /// When a deprecated name is used with srun, sbatch or salloc, a warning
+    /// including `msg` is displayed to the user once.
+    pub fn deprecated(mut self, msg: &str) -> Self {
+        match self.aliases.last_mut() {
+            Some(alias) => alias.deprecated = Some(msg.to_string()),
+            None => self.settings.deprecated = Some(msg.to_string()),
+        }
+        self
+    }
+    /// Makes the option take an optional value, as in `--name[=arg_name]`
+    pub fn takes_optional_value(mut self, arg_name: &str) -> Self {
+        self.arginfo = Some(arg_name.to_string());
+        self.optional_value = true;
+        self
+    }
+    /// Sets a function to check the values of the option
+    ///
+    /// The function is called as soon as Slurm processes the option. If it
+    /// returns an error, the error message is logged and Slurm rejects the
+    /// option, so that srun, sbatch or salloc fail before the job is
+    /// submitted. Values which are not valid UTF-8 are always rejected. Flag
+    /// options, which don't take a value, are not checked.
+    ///
+    /// Values which are not given on the command line are also checked: the
+    /// plugin argument and default value when the option is registered, and
+    /// the environment variable when the value is retrieved.
+    ///
+    ///```rust,no_run
+    /// # use slurm_spank::SpankOption;
+    /// SpankOption::new("renice")
+    ///     .takes_value("prio")
+    ///     .validate(|prio| match prio.parse::<i32>() {
+    ///         Ok(-20..=19) => Ok(()),
+    ///         _ => Err(format!("{} is not a valid priority", prio)),
+    ///     });
+    ///```
+    pub fn validate<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&str) -> Result<(), String> + 'static,
+    {
+        self.settings.validator = Some(Box::new(validator));
+        self
+    }
+    /// Sets an environment variable from which to read the value of the
+    /// option when it is not given on the command line
+    ///
+    /// The variable is read from the environment of srun, sbatch or salloc in
+    /// local and allocator contexts, and from the job environment in remote
+    /// context. See [`SpankHandle::get_option_value`] for the order in which
+    /// values are resolved.
+    pub fn env(mut self, var: &str) -> Self {
+        self.settings.env = Some(var.to_string());
+        self
+    }
+    /// Sets a `plugstack.conf` argument from which to read the value of the
+    /// option when it is not given by the user
+    ///
+    /// With `default_from_plugin_arg("default_mode")`, a `default_mode=<value>`
+    /// plugin argument provides the default value.
+    pub fn default_from_plugin_arg(mut self, key: &str) -> Self {
+        self.settings.plugin_arg = Some(key.to_string());
+        self
+    }
+    /// Sets the value of the option when no other value is found
+    pub fn default(mut self, value: &str) -> Self {
+        self.settings.default = Some(value.to_string());
+        self
+    }
 }
 
 // Slurm may give us NULL pointers for zero length argv. We shouldn't pass them
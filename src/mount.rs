@@ -0,0 +1,445 @@
+use crate::{spank_sys, Context, ProcessLocal, SpankError, SpankHandle};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::raw::c_ulong;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+// Filesystem mounted on a target by this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mounted {
+    Tmpfs { size: Option<u64>, mode: u32 },
+    // Bind mounts are identified by the device and inode of their source, as
+    // the same directory can be reached through several paths
+    Bind { dev: u64, ino: u64 },
+}
+
+// Operations already performed by this process or by the process it was
+// forked from, such as slurmstepd for tasks
+#[derive(Default)]
+struct MountState {
+    unshared: bool,
+    mounts: HashMap<PathBuf, Mounted>,
+    read_only: HashSet<PathBuf>,
+}
+
+static MOUNT_STATE: ProcessLocal<MountState> = ProcessLocal::new();
+
+// Runs `func` with the mount state, reporting its errors as a failure to
+// perform the operation described as `what`
+fn with_mount_state(
+    what: String,
+    func: impl FnOnce(&mut MountState) -> io::Result<()>,
+) -> Result<(), SpankError> {
+    match MOUNT_STATE.with(func) {
+        Some(res) => res.map_err(|e| SpankError::Mount(what, e.to_string())),
+        None => Err(SpankError::Mount(
+            what,
+            "re-entrant mount operation".to_string(),
+        )),
+    }
+}
+
+// Mounts `mounted` on `target` with `op`, unless it already is. Requesting a
+// different mount on a target used by an earlier operation is an error.
+fn mount_once(
+    what: String,
+    target: &Path,
+    mounted: Mounted,
+    op: impl FnOnce() -> io::Result<()>,
+) -> Result<(), SpankError> {
+    with_mount_state(what, |state| {
+        match state.mounts.get(target) {
+            Some(previous) if *previous == mounted => return Ok(()),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "conflicts with an earlier mount on the same target",
+                ))
+            }
+            None => (),
+        }
+        op()?;
+        state.mounts.insert(target.to_path_buf(), mounted);
+        Ok(())
+    })
+}
+
+// Returns the path to show in messages for `path`, which is resolved if it
+// refers to an open file, as done by `JobDir`
+fn display_path(path: &Path) -> PathBuf {
+    if path.starts_with("/proc/self/fd") {
+        if let Ok(target) = path.read_link() {
+            return target;
+        }
+    }
+    path.to_path_buf()
+}
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+fn mount(
+    source: &Path,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: c_ulong,
+    data: Option<&str>,
+) -> io::Result<()> {
+    let source = path_cstring(source)?;
+    let target = path_cstring(target)?;
+    let fstype = fstype.map(|s| CString::new(s).expect("static string"));
+    let data = data
+        .map(CString::new)
+        .transpose()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "data contains a NUL byte"))?;
+
+    let rc = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+            flags,
+            data.as_ref().map_or(ptr::null(), |s| s.as_ptr().cast()),
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Private mount namespace of the current process
+///
+/// A `MountNamespace` is obtained with [`SpankHandle::mount_namespace`], or
+/// with [`MountNamespace::enter`] outside of a plugin. The namespace is
+/// created on first use with `unshare(CLONE_NEWNS)` and mount propagation is
+/// disabled so that its mounts are not visible outside of the job.
+///
+/// When called from `init` in remote context, the namespace is shared by all
+/// the tasks of the step. When called from `task_init_privileged`, each task
+/// gets its own namespace. Every operation is performed at most once per
+/// namespace: repeating it, for instance from each task after it was done in
+/// `init`, does nothing. Operations are identified by their target, and by
+/// their source for bind mounts: requesting a different mount on a target
+/// which was already used, such as a tmpfs of another size, returns an error
+/// instead of mounting over it. Operations require `CAP_SYS_ADMIN`, which plugins
+/// have when running in slurmstepd, or which can be obtained in a user
+/// namespace for testing (e.g. `unshare -Ur`).
+///
+/// # Example
+///
+/// The following plugin gives each job a private `/tmp` backed by a per-job
+/// scratch directory and a size-limited `/dev/shm`:
+///
+///```rust,no_run
+/// use slurm_spank::{Plugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+///
+/// SPANK_PLUGIN!(b"private_tmp", SLURM_VERSION_NUMBER, PrivateTmp);
+///
+/// #[derive(Default)]
+/// struct PrivateTmp {}
+///
+/// unsafe impl Plugin for PrivateTmp {
+///     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         if spank.context()? != slurm_spank::Context::Remote {
+///             return Ok(());
+///         }
+///         let scratch = spank.create_job_dir("/scratch/tmp")?;
+///         let ns = spank.mount_namespace()?;
+///         ns.bind(&scratch, "/tmp")?;
+///         ns.tmpfs("/dev/shm", Some(1 << 30), 0o1777)?;
+///         Ok(())
+///     }
+/// }
+///```
+#[derive(Debug)]
+pub struct MountNamespace {
+    _private: (),
+}
+
+impl MountNamespace {
+    /// Moves the current process to a private mount namespace, unless it
+    /// already is in one created by this crate
+    pub fn enter() -> Result<Self, SpankError> {
+        with_mount_state("unshare mount namespace".to_string(), |state| {
+            if state.unshared {
+                return Ok(());
+            }
+            if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            mount(
+                Path::new("none"),
+                Path::new("/"),
+                None,
+                libc::MS_REC | libc::MS_PRIVATE,
+                None,
+            )?;
+            state.unshared = true;
+            Ok(())
+        })?;
+        Ok(MountNamespace { _private: () })
+    }
+
+    /// Mounts a tmpfs with permissions `mode` on `target`, limited to
+    /// `size` bytes if set
+    ///
+    /// The filesystem is mounted with `nosuid` and `nodev`.
+    pub fn tmpfs<P: AsRef<Path>>(
+        &self,
+        target: P,
+        size: Option<u64>,
+        mode: u32,
+    ) -> Result<(), SpankError> {
+        let target = target.as_ref();
+        let mut data = format!("mode={:o}", mode);
+        if let Some(size) = size {
+            data.push_str(&format!(",size={}", size));
+        }
+        mount_once(
+            format!("mount tmpfs ({}) on {}", data, target.display()),
+            target,
+            Mounted::Tmpfs { size, mode },
+            || {
+                mount(
+                    Path::new("tmpfs"),
+                    target,
+                    Some("tmpfs"),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    Some(&data),
+                )
+            },
+        )
+    }
+
+    /// Mounts an empty tmpfs on `/tmp`
+    pub fn private_tmp(&self) -> Result<(), SpankError> {
+        self.tmpfs("/tmp", None, 0o1777)
+    }
+
+    /// Mounts an empty tmpfs on `/dev/shm`
+    pub fn private_dev_shm(&self) -> Result<(), SpankError> {
+        self.tmpfs("/dev/shm", None, 0o1777)
+    }
+
+    /// Bind mounts `source` on `target`, along with the mounts below
+    /// `source`
+    pub fn bind<S: AsRef<Path>, T: AsRef<Path>>(
+        &self,
+        source: S,
+        target: T,
+    ) -> Result<(), SpankError> {
+        let (source, target) = (source.as_ref(), target.as_ref());
+        let what = format!(
+            "bind mount {} on {}",
+            display_path(source).display(),
+            target.display()
+        );
+        let metadata = source
+            .metadata()
+            .map_err(|e| SpankError::Mount(what.clone(), e.to_string()))?;
+        let mounted = Mounted::Bind {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        };
+        mount_once(what, target, mounted, || {
+            mount(source, target, None, libc::MS_BIND | libc::MS_REC, None)
+        })
+    }
+
+    /// Makes the mount on `target` read-only
+    ///
+    /// `target` is first bind mounted on itself so that other mounts of the
+    /// same filesystem are not affected. The other flags of the mount, such
+    /// as `nosuid`, are kept.
+    pub fn remount_read_only<P: AsRef<Path>>(&self, target: P) -> Result<(), SpankError> {
+        let target = target.as_ref();
+        with_mount_state(format!("remount {} read-only", target.display()), |state| {
+            if state.read_only.contains(target) {
+                return Ok(());
+            }
+            mount(target, target, None, libc::MS_BIND | libc::MS_REC, None)?;
+            let flags = mount_flags(target)?;
+            mount(
+                Path::new("none"),
+                target,
+                None,
+                libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags,
+                None,
+            )?;
+            state.read_only.insert(target.to_path_buf());
+            Ok(())
+        })
+    }
+}
+
+// Returns the flags of the mount on `path` which must be preserved when
+// remounting it. Remounting fails in user namespaces if they are dropped.
+fn mount_flags(path: &Path) -> io::Result<c_ulong> {
+    let c_path = path_cstring(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok([
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .iter()
+    .filter(|(st, _)| stat.f_flag & st != 0)
+    .fold(0, |flags, (_, ms)| flags | ms))
+}
+
+fn check(rc: libc::c_int) -> io::Result<libc::c_int> {
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc)
+    }
+}
+
+/// Per-job directory created with [`SpankHandle::create_job_dir`]
+///
+/// The directory is held open, so that it can be mounted safely even if its
+/// path is changed after its creation: [`AsRef<Path>`] returns a path through
+/// the file descriptor of the directory (`/proc/self/fd/N`), which can be
+/// passed to [`MountNamespace::bind`]. [`JobDir::path`] returns the path of
+/// the directory for display purposes.
+#[derive(Debug)]
+pub struct JobDir {
+    path: PathBuf,
+    fd_path: PathBuf,
+    _fd: OwnedFd,
+}
+
+impl JobDir {
+    /// Creates a directory named after `job_id` in `base`, owned by `uid` and
+    /// `gid` with permissions 0700
+    ///
+    /// The directory is opened from the private mount namespace `ns`, as a
+    /// file descriptor opened from another namespace cannot be bind mounted.
+    ///
+    /// An existing directory is reused, so that this function can be called
+    /// from each task of a job, as long as it is not a symbolic link and is
+    /// owned by root or by `uid`. Its ownership and permissions are reset.
+    /// The directory is created and opened relative to `base` without
+    /// following symbolic links, and modified through its file descriptor.
+    pub fn create<P: AsRef<Path>>(
+        _ns: &MountNamespace,
+        base: P,
+        job_id: u32,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+    ) -> Result<Self, SpankError> {
+        let path = base.as_ref().join(job_id.to_string());
+        Self::create_at(base.as_ref(), job_id, uid, gid)
+            .map_err(|e| {
+                SpankError::Mount(
+                    format!("create job directory {}", path.display()),
+                    e.to_string(),
+                )
+            })
+            .map(|fd| JobDir {
+                fd_path: PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd())),
+                path,
+                _fd: fd,
+            })
+    }
+
+    fn create_at(
+        base: &Path,
+        job_id: u32,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+    ) -> io::Result<OwnedFd> {
+        let c_base = path_cstring(base)?;
+        let name = CString::new(job_id.to_string()).expect("no NUL in number");
+
+        let base = unsafe {
+            OwnedFd::from_raw_fd(check(libc::open(
+                c_base.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            ))?)
+        };
+        if unsafe { libc::mkdirat(base.as_raw_fd(), name.as_ptr(), 0o700) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        let dir = unsafe {
+            OwnedFd::from_raw_fd(check(libc::openat(
+                base.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            ))?)
+        };
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::fstat(dir.as_raw_fd(), &mut stat) })?;
+        if stat.st_uid != 0 && stat.st_uid != uid {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("directory is owned by uid {}", stat.st_uid),
+            ));
+        }
+        if stat.st_uid != uid || stat.st_gid != gid {
+            check(unsafe { libc::fchown(dir.as_raw_fd(), uid, gid) })?;
+        }
+        if stat.st_mode & 0o7777 != 0o700 {
+            check(unsafe { libc::fchmod(dir.as_raw_fd(), 0o700) })?;
+        }
+        Ok(dir)
+    }
+
+    /// Returns the path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for JobDir {
+    fn as_ref(&self) -> &Path {
+        &self.fd_path
+    }
+}
+
+impl SpankHandle<'_> {
+    /// Returns the private mount namespace of the job, creating it if needed
+    ///
+    /// See [`MountNamespace`]. This function returns an error if called
+    /// outside of remote context.
+    pub fn mount_namespace(&self) -> Result<MountNamespace, SpankError> {
+        if self.context()? != Context::Remote {
+            return Err(SpankError::from_spank(
+                "mount_namespace",
+                spank_sys::slurm_err_t_ESPANK_NOT_REMOTE,
+            ));
+        }
+        MountNamespace::enter()
+    }
+
+    /// Creates a directory named after the job id in `base`, owned by the
+    /// job user and only accessible to them
+    ///
+    /// The private mount namespace of the job is created first if needed. See
+    /// [`JobDir::create`]. This function returns an error if called outside
+    /// of remote context.
+    pub fn create_job_dir<P: AsRef<Path>>(&self, base: P) -> Result<JobDir, SpankError> {
+        let ns = self.mount_namespace()?;
+        JobDir::create(&ns, base, self.job_id()?, self.job_uid()?, self.job_gid()?)
+    }
+}
@@ -0,0 +1,318 @@
+use crate::{EnvChange, JobEnv, SpankError, SpankHandle};
+use regex::Regex;
+use std::ffi::{OsStr, OsString};
+use tracing::info;
+
+type RewriteFn = Box<dyn Fn(&OsStr) -> Option<OsString>>;
+
+enum Pattern {
+    Name(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn regex(pattern: &str) -> Result<Self, SpankError> {
+        // Patterns must match whole variable names
+        Regex::new(&format!("^(?:{})$", pattern))
+            .map(Pattern::Regex)
+            .map_err(|e| SpankError::InvalidPattern(pattern.to_string(), e.to_string()))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Name(n) => n == name,
+            Pattern::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+enum Rewrite {
+    CapToCpusPerTask(String),
+    Custom(String, RewriteFn),
+}
+
+/// Policy removing or rewriting job environment variables
+///
+/// Variables matching a deny rule are removed from the job environment unless
+/// they also match an allow rule, so that allow rules can carve exceptions out
+/// of broad deny patterns. Regular expressions must match the whole variable
+/// name. Variables which are kept can then be rewritten.
+///
+/// A policy is applied to the job with [`SpankHandle::apply_env_policy`],
+/// typically from `user_init` or `task_init`, and each change it makes is
+/// logged at the info level.
+///
+/// # Example
+///
+/// The following plugin strips `LD_*` variables except `LD_LIBRARY_PATH`,
+/// caps `OMP_NUM_THREADS` to the number of CPUs per task, and accepts
+/// additional rules from plugstack.conf (see [`EnvPolicy::from_plugin_args`]):
+///
+///```rust,no_run
+/// use slurm_spank::{EnvPolicy, Plugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+///
+/// SPANK_PLUGIN!(b"sanitize", SLURM_VERSION_NUMBER, Sanitize);
+///
+/// #[derive(Default)]
+/// struct Sanitize {}
+///
+/// unsafe impl Plugin for Sanitize {
+///     fn user_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         let policy = EnvPolicy::from_plugin_args(&spank.plugin_argv()?)?
+///             .deny_regex("LD_.*")?
+///             .allow("LD_LIBRARY_PATH")
+///             .cap_to_cpus_per_task("OMP_NUM_THREADS");
+///         spank.apply_env_policy(&policy)?;
+///         Ok(())
+///     }
+/// }
+///```
+#[derive(Default)]
+pub struct EnvPolicy {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    rewrites: Vec<Rewrite>,
+}
+
+impl EnvPolicy {
+    /// Creates a policy which leaves the environment unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy from plugin arguments
+    ///
+    /// The following arguments are recognized. Other arguments starting with
+    /// `env_` are rejected, so that a misspelled rule is reported instead of
+    /// leaving the environment unfiltered, and the remaining ones are ignored:
+    ///
+    /// - `env_allow=NAME[,NAME...]` and `env_deny=NAME[,NAME...]` add allow and
+    ///   deny rules for variable names
+    /// - `env_allow_regex=REGEX` and `env_deny_regex=REGEX` add allow and deny
+    ///   rules for variable names matching a regular expression
+    /// - `env_cap_cpus=NAME[,NAME...]` caps numeric variables to the number of
+    ///   CPUs per task
+    pub fn from_plugin_args<S: AsRef<str>>(args: &[S]) -> Result<Self, SpankError> {
+        let mut policy = Self::new();
+        for arg in args {
+            let arg = arg.as_ref();
+            let invalid = |e: &str| SpankError::InvalidPluginArg(arg.to_string(), e.to_string());
+            let Some((key, value)) = arg.split_once('=') else {
+                if arg.starts_with("env_") {
+                    return Err(invalid("expected KEY=VALUE"));
+                }
+                continue;
+            };
+            let names = || value.split(',').filter(|n| !n.is_empty());
+            policy = match key {
+                "env_allow" => names().fold(policy, EnvPolicy::allow),
+                "env_deny" => names().fold(policy, EnvPolicy::deny),
+                "env_allow_regex" => policy.allow_regex(value)?,
+                "env_deny_regex" => policy.deny_regex(value)?,
+                "env_cap_cpus" => names().fold(policy, EnvPolicy::cap_to_cpus_per_task),
+                _ if key.starts_with("env_") => return Err(invalid("unknown policy argument")),
+                _ => policy,
+            };
+        }
+        Ok(policy)
+    }
+
+    /// Keeps variable `name` even if it matches a deny rule
+    pub fn allow(mut self, name: &str) -> Self {
+        self.allow.push(Pattern::Name(name.to_string()));
+        self
+    }
+
+    /// Keeps variables matching `regex` even if they match a deny rule
+    pub fn allow_regex(mut self, regex: &str) -> Result<Self, SpankError> {
+        self.allow.push(Pattern::regex(regex)?);
+        Ok(self)
+    }
+
+    /// Removes variable `name`
+    pub fn deny(mut self, name: &str) -> Self {
+        self.deny.push(Pattern::Name(name.to_string()));
+        self
+    }
+
+    /// Removes variables matching `regex`
+    pub fn deny_regex(mut self, regex: &str) -> Result<Self, SpankError> {
+        self.deny.push(Pattern::regex(regex)?);
+        Ok(self)
+    }
+
+    /// Lowers variable `name` to the number of CPUs per task if it is a
+    /// larger integer
+    pub fn cap_to_cpus_per_task(mut self, name: &str) -> Self {
+        self.rewrites
+            .push(Rewrite::CapToCpusPerTask(name.to_string()));
+        self
+    }
+
+    /// Rewrites variable `name` with `f` if it is set
+    ///
+    /// `f` receives the current value and returns the new one, or None to
+    /// remove the variable.
+    pub fn rewrite<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(&OsStr) -> Option<OsString> + 'static,
+    {
+        self.rewrites
+            .push(Rewrite::Custom(name.to_string(), Box::new(f)));
+        self
+    }
+
+    fn is_denied(&self, name: &OsStr) -> bool {
+        // Names which are not valid UTF-8 can only match regexes which
+        // accept any character
+        let name = name.to_string_lossy();
+        self.deny.iter().any(|p| p.matches(&name)) && !self.allow.iter().any(|p| p.matches(&name))
+    }
+
+    /// Returns the changes the policy makes to `env`, without applying them
+    ///
+    /// `cpus_per_task` is the limit used by [`cap_to_cpus_per_task`] rules.
+    ///
+    /// [`cap_to_cpus_per_task`]: EnvPolicy::cap_to_cpus_per_task
+    pub fn changes(&self, env: &JobEnv, cpus_per_task: u64) -> Vec<EnvChange> {
+        let mut after = env.clone();
+        for (name, _) in env.iter().filter(|(name, _)| self.is_denied(name)) {
+            after.remove(name);
+        }
+
+        for rewrite in &self.rewrites {
+            let (name, new_value) = match rewrite {
+                Rewrite::CapToCpusPerTask(name) => {
+                    let Some(value) = after.get(name) else {
+                        continue;
+                    };
+                    match value.to_str().and_then(|v| v.trim().parse::<u64>().ok()) {
+                        Some(v) if v > cpus_per_task => {
+                            (name, Some(OsString::from(cpus_per_task.to_string())))
+                        }
+                        _ => continue,
+                    }
+                }
+                Rewrite::Custom(name, f) => {
+                    let Some(value) = after.get(name) else {
+                        continue;
+                    };
+                    (name, f(value))
+                }
+            };
+            match new_value {
+                Some(value) => after.set(name, value),
+                None => {
+                    after.remove(name);
+                }
+            }
+        }
+        after.diff(env)
+    }
+}
+
+impl SpankHandle<'_> {
+    /// Applies `policy` to the job environment and returns the changes made
+    ///
+    /// Each change is logged at the info level. Values of the variables are
+    /// not logged as they may hold sensitive data. This function returns an
+    /// error if called outside of remote context or a
+    /// [`SpankError::EnvApply`] error if some of the changes could not be
+    /// applied.
+    pub fn apply_env_policy(&self, policy: &EnvPolicy) -> Result<Vec<EnvChange>, SpankError> {
+        let cpus_per_task = if policy
+            .rewrites
+            .iter()
+            .any(|r| matches!(r, Rewrite::CapToCpusPerTask(_)))
+        {
+            self.step_cpus_per_task()?
+        } else {
+            u64::MAX
+        };
+
+        let changes = policy.changes(&self.job_env_map()?, cpus_per_task);
+        for change in &changes {
+            match change {
+                EnvChange::Set(name, _) => {
+                    info!("Environment policy rewrote {}", name.to_string_lossy())
+                }
+                EnvChange::Unset(name) => {
+                    info!("Environment policy removed {}", name.to_string_lossy())
+                }
+            }
+        }
+        self.apply_env_changes(&changes)
+            .map_err(SpankError::EnvApply)?;
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(policy: &EnvPolicy, env: &[&str], cpus_per_task: u64) -> Vec<EnvChange> {
+        policy.changes(&JobEnv::parse(env), cpus_per_task)
+    }
+
+    fn unset(name: &str) -> EnvChange {
+        EnvChange::Unset(name.into())
+    }
+
+    #[test]
+    fn allow_overrides_deny_regex() {
+        let policy = EnvPolicy::new()
+            .deny_regex("LD_.*")
+            .unwrap()
+            .allow("LD_LIBRARY_PATH");
+        let env = ["LD_PRELOAD=x.so", "LD_LIBRARY_PATH=/lib", "HOME=/h"];
+        assert_eq!(changes(&policy, &env, 1), [unset("LD_PRELOAD")]);
+    }
+
+    #[test]
+    fn regexes_are_anchored() {
+        let policy = EnvPolicy::new().deny_regex("LD_.*|SECRET").unwrap();
+        let env = [
+            "LD_PRELOAD=x.so",
+            "OLD_LD_PRELOAD=x.so",
+            "SECRET=1",
+            "MY_SECRET_2=1",
+        ];
+        assert_eq!(
+            changes(&policy, &env, 1),
+            [unset("LD_PRELOAD"), unset("SECRET")]
+        );
+    }
+
+    #[test]
+    fn cap_ignores_non_numeric_values() {
+        let policy = EnvPolicy::new()
+            .cap_to_cpus_per_task("A")
+            .cap_to_cpus_per_task("B")
+            .cap_to_cpus_per_task("C")
+            .cap_to_cpus_per_task("D");
+        let env = ["A=16", "B=2", "C=auto", "D=-4"];
+        assert_eq!(
+            changes(&policy, &env, 4),
+            [EnvChange::Set("A".into(), "4".into())]
+        );
+    }
+
+    #[test]
+    fn from_plugin_args() {
+        let policy = EnvPolicy::from_plugin_args(&[
+            "env_deny=A,B",
+            "env_allow=B",
+            "env_cap_cpus=C",
+            "other=ignored",
+        ])
+        .unwrap();
+        assert_eq!(
+            changes(&policy, &["A=1", "B=2", "C=8"], 2),
+            [unset("A"), EnvChange::Set("C".into(), "2".into())]
+        );
+        assert!(EnvPolicy::from_plugin_args(&["env_deny_rgex=LD_.*"]).is_err());
+        assert!(EnvPolicy::from_plugin_args(&["env_deny"]).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::env::{edit_list, ListEdit};
+use crate::{EnvChange, JobEnv, SpankError, SpankHandle};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+// Part of a value, either taken literally or expanded from ${NAME}
+enum Segment {
+    Literal(Vec<u8>),
+    Var(OsString),
+}
+
+enum Directive {
+    Export(OsString, Vec<Segment>),
+    Unset(OsString),
+    PrependPath(OsString, Vec<Segment>),
+}
+
+/// Set of environment changes read from a profile file
+///
+/// Profiles are dotenv-style files with one directive per line:
+///
+/// - `export NAME=VALUE`, or simply `NAME=VALUE`, sets a variable
+/// - `unset NAME` unsets a variable
+/// - `prepend_path NAME VALUE` adds `VALUE` at the beginning of the
+///   `:`-separated list `NAME`, as [`EnvList::prepend`] does
+///
+/// `${NAME}` in a value is replaced with the value of `NAME` at that point of
+/// the profile, or with an empty string if `NAME` is unset. Values can be
+/// quoted: double quotes are removed, single quotes also prevent expansion.
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// Profiles are evaluated against a [`JobEnv`] with [`changes`], which can be
+/// used to show what a profile would do, or applied directly to the job with
+/// [`SpankHandle::apply_env_profile`].
+///
+/// [`EnvList::prepend`]: crate::EnvList::prepend
+/// [`changes`]: EnvProfile::changes
+///
+/// # Example
+///
+/// The following plugin applies the profile selected with `--env-profile`
+/// from the directory given as `profile_dir=` in plugstack.conf:
+///
+///```rust,no_run
+/// use slurm_spank::{
+///     EnvProfile, Plugin, SpankHandle, SpankOption, SLURM_VERSION_NUMBER, SPANK_PLUGIN,
+/// };
+/// use std::error::Error;
+/// use tracing::info;
+///
+/// SPANK_PLUGIN!(b"profiles", SLURM_VERSION_NUMBER, Profiles);
+///
+/// #[derive(Default)]
+/// struct Profiles {}
+///
+/// unsafe impl Plugin for Profiles {
+///     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         spank.register_option(SpankOption::new("env-profile").takes_value("name"))?;
+///         Ok(())
+///     }
+///
+///     fn user_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         let Some(name) = spank.get_option_value("env-profile")? else {
+///             return Ok(());
+///         };
+///         let dir = spank
+///             .plugin_argv()?
+///             .iter()
+///             .find_map(|arg| arg.strip_prefix("profile_dir="))
+///             .unwrap_or("/etc/slurm/env-profiles");
+///         let profile = EnvProfile::load(dir, &name)?;
+///         for change in spank.apply_env_profile(&profile)? {
+///             info!("{}", change);
+///         }
+///         Ok(())
+///     }
+/// }
+///```
+pub struct EnvProfile {
+    name: String,
+    directives: Vec<Directive>,
+}
+
+impl EnvProfile {
+    /// Loads profile `name` from the file `name.env` in `dir`
+    ///
+    /// `name` may only contain alphanumeric characters, `-`, `_` and `.`, and
+    /// cannot start with a `.`, so that user-provided names cannot escape
+    /// `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self, SpankError> {
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return Err(SpankError::EnvProfile(
+                name.to_string(),
+                "invalid profile name".to_string(),
+            ));
+        }
+        let path = dir.as_ref().join(format!("{}.env", name));
+        let content = std::fs::read(&path).map_err(|e| {
+            SpankError::EnvProfile(name.to_string(), format!("{}: {}", path.display(), e))
+        })?;
+        Self::parse(name, content)
+    }
+
+    /// Parses profile `name` from `content`
+    pub fn parse<C: AsRef<[u8]>>(name: &str, content: C) -> Result<Self, SpankError> {
+        let directives = content
+            .as_ref()
+            .split(|&b| b == b'\n')
+            .enumerate()
+            .filter_map(|(i, line)| {
+                parse_line(trim(line)).transpose().map(|res| {
+                    res.map_err(|e| {
+                        SpankError::EnvProfile(name.to_string(), format!("line {}: {}", i + 1, e))
+                    })
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(EnvProfile {
+            name: name.to_string(),
+            directives,
+        })
+    }
+
+    /// Returns the name of the profile
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the changes the profile makes to `env`, without applying them
+    pub fn changes(&self, env: &JobEnv) -> Vec<EnvChange> {
+        let mut after = env.clone();
+        for directive in &self.directives {
+            match directive {
+                Directive::Export(name, value) => {
+                    let value = expand(value, &after);
+                    after.set(name, value);
+                }
+                Directive::Unset(name) => {
+                    after.remove(name);
+                }
+                Directive::PrependPath(name, value) => {
+                    let edits = [ListEdit::Prepend(expand(value, &after))];
+                    match edit_list(after.get(name), b':', &edits) {
+                        Some(value) => after.set(name, value),
+                        None => {
+                            after.remove(name);
+                        }
+                    }
+                }
+            }
+        }
+        after.diff(env)
+    }
+}
+
+impl SpankHandle<'_> {
+    /// Applies `profile` to the job environment and returns the changes made
+    ///
+    /// This function returns an error if called outside of remote context or
+    /// a [`SpankError::EnvApply`] error if some of the changes could not be
+    /// applied.
+    pub fn apply_env_profile(&self, profile: &EnvProfile) -> Result<Vec<EnvChange>, SpankError> {
+        let changes = profile.changes(&self.job_env_map()?);
+        self.apply_env_changes(&changes)
+            .map_err(SpankError::EnvApply)?;
+        Ok(changes)
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<Option<Directive>, String> {
+    if line.is_empty() || line.starts_with(b"#") {
+        return Ok(None);
+    }
+
+    let (keyword, rest) = split_word(line);
+    let directive = match keyword {
+        b"unset" => {
+            let (name, rest) = split_word(rest);
+            if !rest.is_empty() {
+                return Err("unexpected text after variable name".to_string());
+            }
+            Directive::Unset(parse_name(name)?)
+        }
+        b"prepend_path" => {
+            let (name, value) = split_word(rest);
+            if value.is_empty() {
+                return Err("missing value".to_string());
+            }
+            Directive::PrependPath(parse_name(name)?, parse_value(value)?)
+        }
+        b"export" => parse_assignment(rest)?,
+        _ => parse_assignment(line)?,
+    };
+    Ok(Some(directive))
+}
+
+fn trim(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|b| !b.is_ascii_whitespace());
+    let end = s.iter().rposition(|b| !b.is_ascii_whitespace());
+    match (start, end) {
+        (Some(start), Some(end)) => &s[start..=end],
+        _ => &[],
+    }
+}
+
+fn split_word(s: &[u8]) -> (&[u8], &[u8]) {
+    match s.iter().position(|b| b.is_ascii_whitespace()) {
+        Some(pos) => (&s[..pos], trim(&s[pos..])),
+        None => (s, &[]),
+    }
+}
+
+fn parse_assignment(s: &[u8]) -> Result<Directive, String> {
+    let Some(pos) = s.iter().position(|&b| b == b'=') else {
+        return Err("expected NAME=VALUE".to_string());
+    };
+    Ok(Directive::Export(
+        parse_name(&s[..pos])?,
+        parse_value(&s[pos + 1..])?,
+    ))
+}
+
+fn parse_name(name: &[u8]) -> Result<OsString, String> {
+    let valid = matches!(name.first(), Some(b) if b.is_ascii_alphabetic() || *b == b'_')
+        && name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_');
+    if valid {
+        Ok(OsStr::from_bytes(name).to_os_string())
+    } else {
+        Err(format!(
+            "invalid variable name {}",
+            String::from_utf8_lossy(name)
+        ))
+    }
+}
+
+fn parse_value(value: &[u8]) -> Result<Vec<Segment>, String> {
+    let unquoted = |quote: u8| {
+        if value.len() >= 2 && value.ends_with(&[quote]) {
+            Ok(&value[1..value.len() - 1])
+        } else {
+            Err("unterminated quote".to_string())
+        }
+    };
+
+    let value = match value.first() {
+        Some(b'\'') => return Ok(vec![Segment::Literal(unquoted(b'\'')?.to_vec())]),
+        Some(b'"') => unquoted(b'"')?,
+        _ => value,
+    };
+
+    let mut segments = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.windows(2).position(|w| w == b"${") {
+        let Some(len) = rest[start + 2..].iter().position(|&b| b == b'}') else {
+            return Err("unterminated ${".to_string());
+        };
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_vec()));
+        }
+        segments.push(Segment::Var(parse_name(&rest[start + 2..start + 2 + len])?));
+        rest = &rest[start + 3 + len..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_vec()));
+    }
+    Ok(segments)
+}
+
+fn expand(segments: &[Segment], env: &JobEnv) -> OsString {
+    let mut value = Vec::new();
+    for segment in segments {
+        match segment {
+            Segment::Literal(s) => value.extend_from_slice(s),
+            Segment::Var(name) => {
+                if let Some(v) = env.get(name) {
+                    value.extend_from_slice(v.as_bytes());
+                }
+            }
+        }
+    }
+    OsString::from_vec(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(profile: &str, env: &[&str]) -> Vec<EnvChange> {
+        EnvProfile::parse("test", profile)
+            .unwrap()
+            .changes(&JobEnv::parse(env))
+    }
+
+    fn set(name: &str, value: &str) -> EnvChange {
+        EnvChange::Set(name.into(), value.into())
+    }
+
+    fn parse_error(profile: &str) -> String {
+        match EnvProfile::parse("test", profile) {
+            Ok(_) => panic!("{:?} should not parse", profile),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn quotes() {
+        let profile = "A=\"x y\"\nB='${A} z'\nC=\"${A} z\"\nD=\"\"";
+        assert_eq!(
+            changes(profile, &[]),
+            [
+                set("A", "x y"),
+                set("B", "${A} z"),
+                set("C", "x y z"),
+                set("D", "")
+            ]
+        );
+    }
+
+    #[test]
+    fn expansion() {
+        let profile = "export A=${HOME}/bin:${UNSET}\n# comment\n\nB=${A}${A}";
+        assert_eq!(
+            changes(profile, &["HOME=/home/u"]),
+            [
+                set("A", "/home/u/bin:"),
+                set("B", "/home/u/bin:/home/u/bin:")
+            ]
+        );
+    }
+
+    #[test]
+    fn unset_and_prepend_path() {
+        let profile = "unset OLD\nprepend_path PATH ${HOME}/bin";
+        assert_eq!(
+            changes(profile, &["HOME=/h", "OLD=1", "PATH=/usr/bin"]),
+            [
+                EnvChange::Unset("OLD".into()),
+                set("PATH", "/h/bin:/usr/bin")
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(parse_error("A=\"x").ends_with("line 1: unterminated quote"));
+        assert!(parse_error("\nA='x").ends_with("line 2: unterminated quote"));
+        assert!(parse_error("A=${B").ends_with("line 1: unterminated ${"));
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(parse_error("1A=x").ends_with("invalid variable name 1A"));
+        assert!(parse_error("A-B=x").ends_with("invalid variable name A-B"));
+        assert!(parse_error("A=${B-C}").ends_with("invalid variable name B-C"));
+        assert!(parse_error("unset A B").ends_with("unexpected text after variable name"));
+    }
+
+    #[test]
+    fn export_without_assignment() {
+        assert!(parse_error("export A").ends_with("line 1: expected NAME=VALUE"));
+        assert!(parse_error("prepend_path PATH").ends_with("line 1: missing value"));
+    }
+}
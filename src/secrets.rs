@@ -0,0 +1,223 @@
+use crate::{spank_sys, SpankError, SpankHandle};
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::os::raw::c_char;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use zeroize::Zeroizing;
+
+// Variable name and value of a secret
+type Secret = (OsString, Zeroizing<Vec<u8>>);
+
+/// Source of secrets to inject into the environment of tasks
+///
+/// Secrets are read from files in a directory which must be owned by root and
+/// not writable by other users, as must the `uid` and `gid` subdirectories.
+/// The file used for a job is the first existing one among:
+///
+/// - `uid/<uid>` for the uid of the job
+/// - `gid/<gid>` for the primary gid of the job, then for each of its
+///   supplementary gids
+/// - `default`
+///
+/// Secret files must be regular files owned by root and not accessible to
+/// other users (mode `0600` or `0400`). They hold one `NAME=VALUE` entry per
+/// line; empty lines and lines starting with `#` are ignored.
+///
+/// Secrets are injected with [`SpankHandle::inject_secrets`] from
+/// `task_init_privileged`, so that they only appear in the environment of the
+/// tasks and never in the submitted job or its control environment. Buffers
+/// holding secret values are zeroed once the values have been passed to
+/// Slurm, and values are never included in logs or errors.
+///
+/// # Example
+///
+///```rust,no_run
+/// use slurm_spank::{Plugin, SecretStore, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+///
+/// SPANK_PLUGIN!(b"secrets", SLURM_VERSION_NUMBER, Secrets);
+///
+/// #[derive(Default)]
+/// struct Secrets {}
+///
+/// unsafe impl Plugin for Secrets {
+///     fn task_init_privileged(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         spank.inject_secrets(&SecretStore::new("/etc/slurm/secrets"))?;
+///         Ok(())
+///     }
+/// }
+///```
+#[derive(Debug, Clone)]
+pub struct SecretStore {
+    dir: PathBuf,
+}
+
+impl SecretStore {
+    /// Creates a store reading secrets from `dir`
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        SecretStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the secret file to use for a job run as `uid` with groups
+    /// `gids`, if any
+    pub fn select_file(&self, uid: u32, gids: &[u32]) -> Option<PathBuf> {
+        std::iter::once(self.dir.join("uid").join(uid.to_string()))
+            .chain(
+                gids.iter()
+                    .map(|gid| self.dir.join("gid").join(gid.to_string())),
+            )
+            .chain(std::iter::once(self.dir.join("default")))
+            .find(|path| path.symlink_metadata().is_ok())
+    }
+
+    // Reads the secrets from `path` after checking its permissions
+    fn read(&self, path: &Path) -> Result<Vec<Secret>, SpankError> {
+        let secret_error = |e: String| SpankError::SecretFile(path.display().to_string(), e);
+
+        // Users able to modify any directory leading to the file could
+        // change which secrets are selected
+        let mut dir = self.dir.clone();
+        let subdirs = path
+            .parent()
+            .and_then(|parent| parent.strip_prefix(&self.dir).ok())
+            .ok_or_else(|| secret_error("not in the secret directory".to_string()))?;
+        let check_dir = |dir: &Path, metadata: io::Result<Metadata>| {
+            let metadata = metadata.map_err(|e| secret_error(e.to_string()))?;
+            if !metadata.is_dir() {
+                return Err(secret_error(format!(
+                    "{} is not a directory",
+                    dir.display()
+                )));
+            }
+            check_permissions(&metadata, 0o022)
+                .map_err(|e| secret_error(format!("directory {} {}", dir.display(), e)))
+        };
+        check_dir(&dir, dir.metadata())?;
+        for component in subdirs {
+            dir.push(component);
+            check_dir(&dir, dir.symlink_metadata())?;
+        }
+
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(|e| secret_error(e.to_string()))?;
+        let metadata = file.metadata().map_err(|e| secret_error(e.to_string()))?;
+        if !metadata.is_file() {
+            return Err(secret_error("not a regular file".to_string()));
+        }
+        check_permissions(&metadata, 0o077).map_err(secret_error)?;
+
+        // Allocate the whole buffer upfront so that it is not reallocated
+        // while reading, which would leave copies of the secrets behind
+        let len = usize::try_from(metadata.len()).map_err(|e| secret_error(e.to_string()))?;
+        let mut content = Zeroizing::new(Vec::with_capacity(len));
+        file.take(metadata.len())
+            .read_to_end(&mut content)
+            .map_err(|e| secret_error(e.to_string()))?;
+
+        let mut secrets = Vec::new();
+        for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            // Only report the line number so that secrets do not leak into
+            // error messages
+            let Some(pos) = line.iter().position(|&b| b == b'=') else {
+                return Err(secret_error(format!("line {}: expected NAME=VALUE", i + 1)));
+            };
+            let name = &line[..pos];
+            if name.is_empty() || !name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'_') {
+                return Err(secret_error(format!(
+                    "line {}: invalid variable name",
+                    i + 1
+                )));
+            }
+            let value = &line[pos + 1..];
+            if value.contains(&0) {
+                return Err(secret_error(format!(
+                    "line {}: value contains a NUL byte",
+                    i + 1
+                )));
+            }
+            secrets.push((
+                OsString::from_vec(name.to_vec()),
+                Zeroizing::new(value.to_vec()),
+            ));
+        }
+        Ok(secrets)
+    }
+}
+
+// Checks that `metadata` is owned by root and has none of the `forbidden`
+// permission bits
+fn check_permissions(metadata: &Metadata, forbidden: u32) -> Result<(), String> {
+    if metadata.uid() != 0 {
+        return Err("is not owned by root".to_string());
+    }
+    if metadata.mode() & forbidden != 0 {
+        return Err(format!(
+            "has insecure permissions {:o}",
+            metadata.mode() & 0o7777
+        ));
+    }
+    Ok(())
+}
+
+impl SpankHandle<'_> {
+    /// Injects the secrets of `store` for the current job into the task
+    /// environment and returns the names of the variables set
+    ///
+    /// Nothing is injected if no secret file applies to the job. Existing
+    /// variables are overwritten. This function returns an error if called
+    /// outside of remote context, if the secret file does not have secure
+    /// permissions or if it cannot be parsed.
+    pub fn inject_secrets(&self, store: &SecretStore) -> Result<Vec<OsString>, SpankError> {
+        let mut gids = vec![self.job_gid()?];
+        gids.extend(self.job_supplementary_gids()?);
+
+        let Some(path) = store.select_file(self.job_uid()?, &gids) else {
+            debug!("No secret file for this job");
+            return Ok(Vec::new());
+        };
+
+        let mut names = Vec::new();
+        for (name, value) in store.read(&path)? {
+            self.setenv_secret(&name, &value)?;
+            names.push(name);
+        }
+        debug!("Injected {} secrets from {}", names.len(), path.display());
+        Ok(names)
+    }
+
+    // Sets `name` in the job environment without leaving copies of `value`
+    // in memory. Names and values were checked not to contain NUL bytes when
+    // they were read.
+    fn setenv_secret(&self, name: &OsString, value: &[u8]) -> Result<(), SpankError> {
+        let mut c_name = name.as_bytes().to_vec();
+        c_name.push(0);
+
+        let mut c_value = Zeroizing::new(Vec::with_capacity(value.len() + 1));
+        c_value.extend_from_slice(value);
+        c_value.push(0);
+
+        match unsafe {
+            spank_sys::spank_setenv(
+                self.spank,
+                c_name.as_ptr() as *const c_char,
+                c_value.as_ptr() as *const c_char,
+                1,
+            )
+        } {
+            spank_sys::ESPANK_SUCCESS => Ok(()),
+            e => Err(SpankError::from_spank("spank_setenv", e)),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{error_report, Plugin, PluginCallback, SpankHandle};
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use tracing::{error, span};
+
+/// Determines how a [`PluginStack`] handles errors returned by its modules
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum StackErrorMode {
+    /// Stop at the first module returning an error
+    #[default]
+    FailFast,
+    /// Run the callback of every module and return all their errors
+    ContinueAndAggregate,
+}
+
+/// Plugin combining several independent plugins, or modules, into a single
+/// SPANK plugin
+///
+/// Each SPANK callback is dispatched to the modules in the order in which they
+/// were added. Options registered by a module are namespaced with the module
+/// name: a `prio` option registered by a `renice` module is available as
+/// `--renice-prio`, and is queried as `prio` from within the module.
+///
+/// Errors returned by a module are reported through its own
+/// [`Plugin::report_error`] method before the stack returns a [`StackError`].
+/// The `setup` method of the modules is not called: the stack sets up tracing
+/// once for all modules.
+///
+/// # Example
+///
+///```rust,no_run
+/// use slurm_spank::{Plugin, PluginStack, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+/// # #[derive(Default)]
+/// # struct Scratch {}
+/// # unsafe impl Plugin for Scratch {}
+/// # #[derive(Default)]
+/// # struct Renice {}
+/// # unsafe impl Plugin for Renice {}
+///
+/// SPANK_PLUGIN!(b"site", SLURM_VERSION_NUMBER, PluginStack, site_stack);
+///
+/// fn site_stack(_spank: &mut SpankHandle) -> Result<PluginStack, Box<dyn Error>> {
+///     Ok(PluginStack::new()
+///         .module("scratch", Scratch::default())
+///         .module("renice", Renice::default()))
+/// }
+///```
+#[derive(Default)]
+pub struct PluginStack {
+    modules: Vec<(String, Box<dyn Plugin>)>,
+    error_mode: StackErrorMode,
+    // Set when the last callback failed with errors already reported by the
+    // modules themselves
+    errors_reported: Cell<bool>,
+}
+
+impl PluginStack {
+    /// Creates an empty stack which stops at the first error
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how errors returned by modules are handled
+    pub fn error_mode(mut self, error_mode: StackErrorMode) -> Self {
+        self.error_mode = error_mode;
+        self
+    }
+
+    /// Adds a module to the stack under `name`
+    ///
+    /// `name` is used as the namespace of the module's options and to identify
+    /// the module in logs and errors.
+    pub fn module<P: Plugin + 'static>(mut self, name: &str, plugin: P) -> Self {
+        self.modules.push((name.to_string(), Box::new(plugin)));
+        self
+    }
+
+    fn dispatch(
+        &mut self,
+        spank: &mut SpankHandle,
+        cb: PluginCallback,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+
+        for (name, module) in self.modules.iter_mut() {
+            let span = span!(tracing::Level::DEBUG, "module", name = name.as_str());
+            let _guard = span.enter();
+
+            let res = spank.with_option_prefix(name, |spank| {
+                let res = cb(module.as_mut(), spank);
+                if let Err(e) = &res {
+                    module.report_error(spank, e.as_ref());
+                }
+                res
+            });
+
+            if let Err(e) = res {
+                errors.push((name.clone(), e));
+                if self.error_mode == StackErrorMode::FailFast {
+                    break;
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            self.errors_reported.set(true);
+            Err(Box::new(StackError { errors }))
+        }
+    }
+}
+
+/// Error returned by a [`PluginStack`] when some of its modules failed
+#[derive(Debug)]
+pub struct StackError {
+    errors: Vec<(String, Box<dyn Error>)>,
+}
+
+impl StackError {
+    /// Returns the name and error of each module which failed
+    pub fn errors(&self) -> impl Iterator<Item = (&str, &dyn Error)> {
+        self.errors
+            .iter()
+            .map(|(name, error)| (name.as_str(), error.as_ref()))
+    }
+}
+
+impl Error for StackError {}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, error)) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", name, error_report(error.as_ref()))?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! stack_callbacks {
+    ($($cb:ident),*) => {
+        $(
+            fn $cb(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+                self.dispatch(spank, |module, spank| module.$cb(spank))
+            }
+        )*
+    };
+}
+
+unsafe impl Plugin for PluginStack {
+    stack_callbacks!(
+        init,
+        job_prolog,
+        init_post_opt,
+        local_user_init,
+        user_init,
+        task_init_privileged,
+        task_init,
+        task_post_fork,
+        task_exit,
+        job_epilog,
+        slurmd_exit,
+        exit
+    );
+
+    fn report_error(&self, _spank: &mut SpankHandle, error: &dyn Error) {
+        if !self.errors_reported.replace(false) {
+            error!("{}", error_report(error));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{spank_sys, Context, SpankError, SpankHandle};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+
+// Version of the envelope in which states are encoded. It must be bumped
+// whenever the encoding changes so that mismatched plugin versions between
+// the submission host and the compute nodes are detected.
+const STATE_FORMAT_VERSION: u32 = 1;
+
+/// Maximum size in bytes of an encoded state
+///
+/// States travel through environment variables which are copied into every
+/// job step and task, so they should be kept small.
+pub const MAX_STATE_SIZE: usize = 32 * 1024;
+
+#[derive(Serialize)]
+struct StateEnvelopeRef<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct StateEnvelope {
+    version: u32,
+    data: serde_json::Value,
+}
+
+// Converts `s` into a string suitable for an environment variable name
+fn env_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl SpankHandle<'_> {
+    /// Exports `value` under `key` so that it can be retrieved with
+    /// [`import_state`] on the compute nodes
+    ///
+    /// The value is encoded as JSON in the job control environment, which
+    /// Slurm passes to slurmstepd and to the job prolog and epilog. The
+    /// environment variable is named after the plugin and `key`, which may
+    /// only contain alphanumeric characters, `-` and `_`.
+    ///
+    /// The state is exported from srun, sbatch or salloc, which run as the
+    /// user: it must not contain secrets, and the user may replace it with
+    /// arbitrary values before the job starts. See [`import_state`].
+    ///
+    /// This function returns an error if called outside of local or allocator
+    /// context, typically from `init_post_opt`, or if the encoded value is
+    /// larger than [`MAX_STATE_SIZE`].
+    ///
+    /// [`import_state`]: SpankHandle::import_state
+    pub fn export_state<T: Serialize>(&self, key: &str, value: &T) -> Result<(), SpankError> {
+        let var = self.state_var(key)?;
+        match self.context()? {
+            Context::Local | Context::Allocator => (),
+            _ => {
+                return Err(SpankError::from_spank(
+                    "export_state",
+                    spank_sys::slurm_err_t_ESPANK_NOT_LOCAL,
+                ))
+            }
+        }
+
+        let encoded = serde_json::to_string(&StateEnvelopeRef {
+            version: STATE_FORMAT_VERSION,
+            data: value,
+        })
+        .map_err(|e| SpankError::StateEncoding(key.to_string(), e.to_string()))?;
+        if encoded.len() > MAX_STATE_SIZE {
+            return Err(SpankError::StateTooLarge(key.to_string(), encoded.len()));
+        }
+
+        self.job_control_setenv(&var, &encoded, true)
+    }
+
+    /// Retrieves the value exported under `key` with [`export_state`]
+    ///
+    /// This function returns Ok(None) if no value was exported. It returns an
+    /// error if called outside of remote or job script context, or if the
+    /// value cannot be decoded as a `T`, including when it was exported with
+    /// an incompatible version of this crate.
+    ///
+    /// The imported value is untrusted input: it is produced by commands run
+    /// as the user, who can set it to any value which decodes as a `T`.
+    /// Remote callbacks must validate it like any other user input, for
+    /// instance against the limits of the job, before acting on it.
+    ///
+    /// [`export_state`]: SpankHandle::export_state
+    pub fn import_state<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, SpankError> {
+        let var = self.state_var(key)?;
+        let encoded = match self.context()? {
+            // Slurm exports the job control environment to slurmstepd and to
+            // the prolog and epilog with a prefix
+            Context::Remote | Context::JobScript => {
+                std::env::var_os(format!("SLURM_SPANK_{}", var))
+            }
+            _ => {
+                return Err(SpankError::from_spank(
+                    "import_state",
+                    spank_sys::slurm_err_t_ESPANK_NOT_REMOTE,
+                ))
+            }
+        };
+        let Some(encoded) = encoded else {
+            return Ok(None);
+        };
+        decode_state(key, encoded).map(Some)
+    }
+
+    // Returns the name of the environment variable holding state `key`
+    fn state_var(&self, key: &str) -> Result<String, SpankError> {
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(SpankError::StateEncoding(
+                key.to_string(),
+                "invalid key".to_string(),
+            ));
+        }
+        Ok(format!(
+            "SPANK_RS_STATE_{}_{}",
+            env_name(self.plugin_name()),
+            env_name(key)
+        ))
+    }
+}
+
+fn decode_state<T: DeserializeOwned>(key: &str, encoded: OsString) -> Result<T, SpankError> {
+    let decode_error = |e: String| SpankError::StateEncoding(key.to_string(), e);
+
+    if encoded.len() > MAX_STATE_SIZE {
+        return Err(SpankError::StateTooLarge(key.to_string(), encoded.len()));
+    }
+    let encoded = encoded
+        .into_string()
+        .map_err(|_| decode_error("not valid UTF-8".to_string()))?;
+
+    let envelope: StateEnvelope =
+        serde_json::from_str(&encoded).map_err(|e| decode_error(e.to_string()))?;
+    if envelope.version != STATE_FORMAT_VERSION {
+        return Err(decode_error(format!(
+            "unsupported format version {}",
+            envelope.version
+        )));
+    }
+    serde_json::from_value(envelope.data).map_err(|e| decode_error(e.to_string()))
+}
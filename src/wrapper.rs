@@ -0,0 +1,274 @@
+use crate::{SpankError, SpankHandle};
+use libc::{gid_t, uid_t};
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use tracing::{debug, info};
+
+// Search path used by execvp(3) when PATH is not set
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+type TaskFilter = Box<dyn Fn(u32) -> bool>;
+
+/// Command prepended to the command line of tasks, such as a container
+/// runtime, a profiler or `numactl`
+///
+/// A wrapper is applied with [`SpankHandle::wrap_task`] from `task_init` or
+/// `task_init_privileged`. Before the command line is modified, the user
+/// command is resolved through the `PATH` of the job environment, and both the
+/// wrapper and the user command are checked to be executable by the job user,
+/// so that a misconfiguration is reported clearly instead of failing at
+/// execve(2) time. Tasks whose command line already starts with the wrapper
+/// and its arguments are left untouched.
+///
+/// # Example
+///
+/// The following plugin runs the even tasks of the job under `numactl`:
+///
+///```rust,no_run
+/// use slurm_spank::{Plugin, SpankHandle, TaskWrapper, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+/// use std::error::Error;
+///
+/// SPANK_PLUGIN!(b"numa", SLURM_VERSION_NUMBER, Numa);
+///
+/// #[derive(Default)]
+/// struct Numa {}
+///
+/// unsafe impl Plugin for Numa {
+///     fn task_init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+///         let wrapper = TaskWrapper::new("/usr/bin/numactl")
+///             .arg("--interleave=all")
+///             .tasks(|id| id % 2 == 0);
+///         spank.wrap_task(&wrapper)?;
+///         Ok(())
+///     }
+/// }
+///```
+pub struct TaskWrapper {
+    program: OsString,
+    args: Vec<OsString>,
+    filter: Option<TaskFilter>,
+}
+
+impl TaskWrapper {
+    /// Creates a wrapper running `program`
+    ///
+    /// `program` must be an absolute path. It is not looked up in the `PATH`
+    /// of the job environment, which is controlled by the user.
+    pub fn new<P: AsRef<OsStr>>(program: P) -> Self {
+        TaskWrapper {
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            filter: None,
+        }
+    }
+
+    /// Adds an argument passed to the wrapper before the user command
+    pub fn arg<A: AsRef<OsStr>>(mut self, arg: A) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds arguments passed to the wrapper before the user command
+    pub fn args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    /// Only wraps tasks whose global task id is accepted by `filter`
+    pub fn tasks<F: Fn(u32) -> bool + 'static>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+}
+
+impl SpankHandle<'_> {
+    /// Resolves `command` as the job would when executing it
+    ///
+    /// Commands which do not contain a `/` are looked up in the `PATH` of the
+    /// job environment. The result must be a regular file which the job user
+    /// may execute, as checked by access(2) with the credentials of the job
+    /// user, which takes ACLs, directory permissions and `noexec` mounts into
+    /// account. When called as root, the effective credentials of the calling
+    /// thread are switched to those of the job user during the check.
+    ///
+    /// This function returns Ok(None) if no such file is found and an error if
+    /// called outside of remote context or if the credentials cannot be
+    /// switched.
+    pub fn resolve_command<C: AsRef<OsStr>>(
+        &self,
+        command: C,
+    ) -> Result<Option<PathBuf>, SpankError> {
+        let command = command.as_ref();
+        let uid = self.job_uid()?;
+        let gid = self.job_gid()?;
+        let mut gids = vec![gid];
+        gids.extend(self.job_supplementary_gids()?);
+
+        if command.is_empty() {
+            return Ok(None);
+        }
+        let candidates = if command.as_bytes().contains(&b'/') {
+            vec![PathBuf::from(command)]
+        } else {
+            let path = self
+                .getenv_os("PATH")?
+                .unwrap_or_else(|| OsString::from(DEFAULT_PATH));
+            path.as_bytes()
+                .split(|&b| b == b':')
+                .map(|dir| {
+                    // An empty component stands for the current directory
+                    let dir = if dir.is_empty() { b"." } else { dir };
+                    Path::new(OsStr::from_bytes(dir)).join(command)
+                })
+                .collect()
+        };
+
+        with_job_credentials(uid, gid, &gids, || {
+            candidates.into_iter().find(|path| is_executable(path))
+        })
+        .map_err(|e| SpankError::JobCredentials(e.to_string()))
+    }
+
+    /// Prepends `wrapper` to the command line of the current task
+    ///
+    /// This function returns Ok(false) if the task was not wrapped, either
+    /// because it is excluded by [`TaskWrapper::tasks`] or because its command
+    /// line already starts with the wrapper and its arguments. It returns an
+    /// error if the wrapper is not an absolute path, if the wrapper or the
+    /// user command cannot be resolved, or if called outside of a task
+    /// context.
+    pub fn wrap_task(&self, wrapper: &TaskWrapper) -> Result<bool, SpankError> {
+        let task_id = self.task_global_id()?;
+        if let Some(filter) = &wrapper.filter {
+            if !filter(task_id) {
+                return Ok(false);
+            }
+        }
+
+        let wrapper_error =
+            |e: String| SpankError::TaskWrapper(wrapper.program.to_string_lossy().into_owned(), e);
+
+        if !Path::new(&wrapper.program).is_absolute() {
+            return Err(wrapper_error("not an absolute path".to_string()));
+        }
+        let program = self
+            .resolve_command(&wrapper.program)?
+            .ok_or_else(|| wrapper_error("not found or not executable".to_string()))?;
+
+        let argv = self.job_argv_os()?;
+        let Some(command) = argv.first() else {
+            return Err(wrapper_error("task has no command".to_string()));
+        };
+        let command = self.resolve_command(command)?.ok_or_else(|| {
+            wrapper_error(format!(
+                "command {} not found or not executable",
+                command.to_string_lossy()
+            ))
+        })?;
+
+        // Only a command line starting with the same executable and the same
+        // arguments is considered wrapped, so that users cannot skip the
+        // wrapper by naming their own program after it
+        let same_program = match (command.canonicalize(), program.canonicalize()) {
+            (Ok(command), Ok(program)) => command == program,
+            _ => false,
+        };
+        let same_args = argv.len() > wrapper.args.len()
+            && wrapper.args.iter().zip(&argv[1..]).all(|(a, b)| a == b);
+        if same_program && same_args {
+            debug!(
+                "Task {} is already wrapped by {}",
+                task_id,
+                program.display()
+            );
+            return Ok(false);
+        }
+
+        let mut wrapper_argv = vec![program.as_os_str()];
+        wrapper_argv.extend(wrapper.args.iter().map(OsString::as_os_str));
+
+        info!(
+            "Running task {} as: {}",
+            task_id,
+            wrapper_argv
+                .iter()
+                .chain(argv.iter())
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        self.prepend_task_argv_os(wrapper_argv)?;
+        Ok(true)
+    }
+}
+
+// Checks whether `path` is a regular file which can be executed with the
+// effective credentials of the calling thread
+fn is_executable(path: &Path) -> bool {
+    if !path.metadata().is_ok_and(|metadata| metadata.is_file()) {
+        return false;
+    }
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::X_OK,
+            libc::AT_EACCESS,
+        ) == 0
+    }
+}
+
+// Runs `func` with the effective credentials of the calling thread set to
+// those of the job user, unless they already are. Raw system calls are used
+// as the libc wrappers change the credentials of every thread of the process.
+fn with_job_credentials<R>(
+    uid: uid_t,
+    gid: gid_t,
+    gids: &[gid_t],
+    func: impl FnOnce() -> R,
+) -> io::Result<R> {
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid == uid {
+        return Ok(func());
+    }
+
+    let count = unsafe { libc::getgroups(0, ptr::null_mut()) };
+    let mut groups = vec![0; usize::try_from(count).map_err(|_| io::Error::last_os_error())?];
+    if unsafe { libc::getgroups(count, groups.as_mut_ptr()) } != count {
+        return Err(io::Error::last_os_error());
+    }
+
+    let res = set_credentials(uid, gid, gids).map(|()| func());
+    // Restore the credentials even if they were only partially switched.
+    // The user id comes first so that the privileges to restore the groups
+    // are regained.
+    check(unsafe { libc::syscall(libc::SYS_setresuid, uid_t::MAX, euid, uid_t::MAX) })?;
+    check(unsafe { libc::syscall(libc::SYS_setresgid, gid_t::MAX, egid, gid_t::MAX) })?;
+    check(unsafe { libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr()) })?;
+    res
+}
+
+fn set_credentials(uid: uid_t, gid: gid_t, gids: &[gid_t]) -> io::Result<()> {
+    check(unsafe { libc::syscall(libc::SYS_setgroups, gids.len(), gids.as_ptr()) })?;
+    check(unsafe { libc::syscall(libc::SYS_setresgid, gid_t::MAX, gid, gid_t::MAX) })?;
+    check(unsafe { libc::syscall(libc::SYS_setresuid, uid_t::MAX, uid, uid_t::MAX) })
+}
+
+fn check(rc: libc::c_long) -> io::Result<()> {
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * SPANK plugin written in C, run by the foreign test plugin through
+ * ForeignSpankPlugin
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include <slurm/spank.h>
+
+SPANK_PLUGIN(foreign_c, 1);
+
+static char greeting[64] = "nobody";
+
+static int greet_cb(int val, const char *optarg, int remote)
+{
+	snprintf(greeting, sizeof(greeting), "%s", optarg);
+	return 0;
+}
+
+struct spank_option spank_options[] = {
+	{ "foreign-greet", "name", "Greet from a C plugin", 1, 0, greet_cb },
+	SPANK_OPTIONS_TABLE_END
+};
+
+static void log_callback(const char *callback, int ac, char **av)
+{
+	char args[256] = "";
+	int i;
+
+	for (i = 0; i < ac; i++) {
+		if (i > 0)
+			strncat(args, ",", sizeof(args) - strlen(args) - 1);
+		strncat(args, av[i], sizeof(args) - strlen(args) - 1);
+	}
+
+	slurm_spank_log("foreign_c %s: greeting %s, argv %s", callback,
+			greeting, args);
+}
+
+int slurm_spank_init_post_opt(spank_t sp, int ac, char **av)
+{
+	if (spank_context() == S_CTX_LOCAL)
+		log_callback("init_post_opt", ac, av);
+	return 0;
+}
+
+int slurm_spank_user_init(spank_t sp, int ac, char **av)
+{
+	log_callback("user_init", ac, av);
+	return 0;
+}
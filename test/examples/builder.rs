@@ -0,0 +1,32 @@
+use slurm_spank::{
+    spank_log_user, SpankOption, SpankPluginBuilder, SLURM_VERSION_NUMBER, SPANK_PLUGIN_BUILDER,
+};
+
+// Plugin built with SpankPluginBuilder, named after the symbol exported here
+SPANK_PLUGIN_BUILDER!(b"builder", SLURM_VERSION_NUMBER, builder);
+
+fn builder() -> SpankPluginBuilder<Option<String>> {
+    SpankPluginBuilder::with_state(None)
+        .option(
+            SpankOption::new("builder-greet")
+                .takes_value("name")
+                .usage("Greet from a builder plugin"),
+        )
+        .on_init_post_opt(|greet, spank| {
+            *greet = spank
+                .get_option_value("builder-greet")?
+                .map(|name| name.to_string());
+            Ok(())
+        })
+        .on_user_init(|greet, spank| {
+            if let Some(name) = greet {
+                spank_log_user!(
+                    "{:?}: {} greets {}",
+                    spank.context()?,
+                    spank.plugin_name(),
+                    name
+                );
+            }
+            Ok(())
+        })
+}
@@ -0,0 +1,13 @@
+use eyre::eyre;
+use slurm_spank::{ForeignSpankPlugin, SpankHandle, SLURM_VERSION_NUMBER, SPANK_PLUGIN};
+use std::error::Error;
+
+// Runs the C plugin given as first plugin argument. All the plugin arguments
+// are forwarded to its callbacks.
+SPANK_PLUGIN!(b"foreign", SLURM_VERSION_NUMBER, ForeignSpankPlugin, open);
+
+fn open(spank: &mut SpankHandle) -> Result<ForeignSpankPlugin, Box<dyn Error>> {
+    let argv = spank.plugin_argv()?;
+    let path = argv.first().ok_or_else(|| eyre!("missing plugin path"))?;
+    Ok(ForeignSpankPlugin::open(path)?)
+}
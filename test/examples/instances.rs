@@ -0,0 +1,42 @@
+use eyre::eyre;
+use slurm_spank::{
+    spank_log_user, Plugin, SpankHandle, SpankOption, SLURM_VERSION_NUMBER, SPANK_PLUGIN,
+};
+use std::error::Error;
+
+// Plugin listed twice in plugstack.conf with different arguments. Each
+// instance registers a --instance-<name> option named after its name=<name>
+// argument.
+SPANK_PLUGIN!(b"instances", SLURM_VERSION_NUMBER, Instance);
+
+#[derive(Default)]
+struct Instance {
+    name: String,
+}
+
+unsafe impl Plugin for Instance {
+    fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        self.name = spank
+            .plugin_argv()?
+            .iter()
+            .find_map(|arg| arg.strip_prefix("name="))
+            .ok_or_else(|| eyre!("missing name argument"))?
+            .to_string();
+        spank.register_option(
+            SpankOption::new(&format!("instance-{}", self.name)).takes_value("value"),
+        )?;
+        Ok(())
+    }
+
+    fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        let value = spank.get_option_value(&format!("instance-{}", self.name))?;
+        spank_log_user!(
+            "{:?}: instance {}: value {}, argv {}",
+            spank.context()?,
+            self.name,
+            value.as_deref().unwrap_or("none"),
+            spank.plugin_argv()?.join(",")
+        );
+        Ok(())
+    }
+}
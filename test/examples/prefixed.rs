@@ -0,0 +1,33 @@
+use slurm_spank::{
+    spank_log_user, Plugin, SpankError, SpankHandle, SpankOption, SLURM_VERSION_NUMBER,
+    SPANK_PLUGIN,
+};
+use std::error::Error;
+
+// Plugin whose options are prefixed with its name: greet is registered as
+// --prefixed-greet
+SPANK_PLUGIN!(b"prefixed", SLURM_VERSION_NUMBER, Prefixed);
+
+#[derive(Default)]
+struct Prefixed {}
+
+unsafe impl Plugin for Prefixed {
+    fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        spank.prefix_option_names();
+        spank.register_option(SpankOption::new("greet").takes_value("name"))?;
+
+        // Clashes are reported with the full name of the option
+        match spank.register_option(SpankOption::new("greet")) {
+            Err(SpankError::OptionExists { name }) => spank_log_user!("option exists: {}", name),
+            res => panic!("Expected an OptionExists error, got {:?}", res),
+        }
+        Ok(())
+    }
+
+    fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        if let Some(name) = spank.get_option_value("greet")? {
+            spank_log_user!("{:?}: prefixed greets {}", spank.context()?, name);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use eyre::eyre;
+use slurm_spank::{
+    spank_log_user, Context, Plugin, PluginStack, SpankHandle, SpankOption, StackErrorMode,
+    SLURM_VERSION_NUMBER, SPANK_PLUGIN,
+};
+use std::error::Error;
+
+// Two nested stacks with the same modules, one for each error mode. The outer
+// stack aggregates errors so that both inner stacks always run.
+SPANK_PLUGIN!(b"stack", SLURM_VERSION_NUMBER, PluginStack, stack);
+
+fn stack(_spank: &mut SpankHandle) -> Result<PluginStack, Box<dyn Error>> {
+    Ok(PluginStack::new()
+        .error_mode(StackErrorMode::ContinueAndAggregate)
+        .module("failfast", modules("failfast", StackErrorMode::FailFast))
+        .module(
+            "aggregate",
+            modules("aggregate", StackErrorMode::ContinueAndAggregate),
+        ))
+}
+
+fn modules(stack: &str, error_mode: StackErrorMode) -> PluginStack {
+    PluginStack::new()
+        .error_mode(error_mode)
+        .module("first", Module::new(stack, "first"))
+        .module("second", Module::new(stack, "second"))
+}
+
+// Module registering a --fail flag, namespaced as --<stack>-<module>-fail,
+// which makes it fail in init_post_opt
+struct Module {
+    label: String,
+}
+
+impl Module {
+    fn new(stack: &str, module: &str) -> Self {
+        Module {
+            label: format!("{} {}", stack, module),
+        }
+    }
+}
+
+unsafe impl Plugin for Module {
+    fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        spank.register_option(SpankOption::new("fail").usage("Fail in init_post_opt"))?;
+        Ok(())
+    }
+
+    fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        if spank.context()? != Context::Local {
+            return Ok(());
+        }
+        if spank.is_option_set("fail") {
+            return Err(eyre!("{} failed", self.label).into());
+        }
+        spank_log_user!("{} ran", self.label);
+        Ok(())
+    }
+
+    fn report_error(&self, _spank: &mut SpankHandle, error: &dyn Error) {
+        spank_log_user!("{} reported: {}", self.label, error);
+    }
+}
@@ -0,0 +1,93 @@
+//! Exercises job directories and mount namespaces outside of Slurm. Must be
+//! run with mount privileges, for instance in a user namespace:
+//!
+//!     unshare -Ur job_dir BASE
+use slurm_spank::{JobDir, MountNamespace};
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+// Normally provided by Slurm when the plugin is loaded
+#[no_mangle]
+extern "C" fn spank_strerror(_err: u32) -> *const std::os::raw::c_char {
+    std::ptr::null()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let base = Path::new(&std::env::args().nth(1).ok_or("usage: job_dir BASE")?).to_owned();
+    let target = base.join("target");
+    let decoy = base.join("decoy");
+    fs::create_dir(&target)?;
+    fs::create_dir(&decoy)?;
+
+    let ns = MountNamespace::enter()?;
+
+    // Symbolic links are not followed
+    symlink(&decoy, base.join("1"))?;
+    match JobDir::create(&ns, &base, 1, 0, 0) {
+        Ok(_) => println!("symlink followed"),
+        Err(e) => println!("symlink rejected: {}", e),
+    }
+
+    // Existing directories are reused with permissions reset to 0700
+    fs::create_dir(base.join("2"))?;
+    fs::set_permissions(base.join("2"), fs::Permissions::from_mode(0o755))?;
+    let dir = JobDir::create(&ns, &base, 2, 0, 0)?;
+    let mode = fs::metadata(dir.path())?.permissions().mode();
+    println!("job dir mode: {:o}", mode & 0o7777);
+
+    // The directory is mounted through its file descriptor even if its path
+    // is replaced in the meantime
+    fs::rename(dir.path(), base.join("moved"))?;
+    symlink(&decoy, dir.path())?;
+    ns.bind(&dir, &target)?;
+    fs::write(target.join("marker"), "")?;
+    if base.join("moved/marker").exists() {
+        println!("bind mounted job dir");
+    }
+    if decoy.join("marker").exists() {
+        println!("bind mounted decoy");
+    }
+
+    // Repeated operations are identified by their target and source, so
+    // binding the same directory through another descriptor does nothing
+    fs::remove_file(dir.path())?;
+    fs::rename(base.join("moved"), dir.path())?;
+    let mounts = mount_count(&target)?;
+    let again = JobDir::create(&ns, &base, 2, 0, 0)?;
+    ns.bind(&again, &target)?;
+    if mount_count(&target)? == mounts {
+        println!("repeated bind ignored");
+    }
+    let other = JobDir::create(&ns, &base, 3, 0, 0)?;
+    if let Err(e) = ns.bind(&other, &target) {
+        println!("conflicting bind rejected: {}", e);
+    }
+
+    ns.remount_read_only(&target)?;
+    if fs::write(target.join("marker"), "").is_err() {
+        println!("remounted read-only");
+    }
+
+    let shm = base.join("shm");
+    fs::create_dir(&shm)?;
+    ns.tmpfs(&shm, Some(1 << 20), 0o1777)?;
+    fs::write(shm.join("marker"), "")?;
+    if !decoy.join("marker").exists() && shm.join("marker").exists() {
+        println!("mounted tmpfs");
+    }
+    if let Err(e) = ns.tmpfs(&shm, None, 0o1777) {
+        println!("conflicting tmpfs rejected: {}", e);
+    }
+    Ok(())
+}
+
+// Counts the mounts on `target` in the current namespace
+fn mount_count(target: &Path) -> Result<usize, Box<dyn Error>> {
+    let target = target.to_str().ok_or("non UTF-8 path")?;
+    Ok(fs::read_to_string("/proc/self/mountinfo")?
+        .lines()
+        .filter(|line| line.split(' ').nth(4) == Some(target))
+        .count())
+}
@@ -1,13 +1,13 @@
 use eyre::eyre;
-use slurm_spank::{
-    spank_log_user, Context, Plugin, SpankHandle, SpankOption, SLURM_VERSION_NUMBER, SPANK_PLUGIN,
-};
+use slurm_spank::{spank_log_user, Context, Plugin, SpankError, SpankHandle, SpankOption};
 use std::convert::TryFrom;
 use std::error::Error;
-use tracing::info;
-
-SPANK_PLUGIN!(b"tests", SLURM_VERSION_NUMBER, SpankTest);
+use tracing::{debug, info};
 
+#[slurm_spank::plugin(
+    name = "tests",
+    option(name = "test-static", arg = "value", usage = "Static option test"),
+)]
 #[derive(Default)]
 struct SpankTest {}
 
@@ -15,13 +15,35 @@ unsafe impl Plugin for SpankTest {
     fn init(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
         let context = spank.context()?;
 
-        let usage = match spank.context()? {
-            Context::Local => "Run selected test (srun)",
-            Context::Allocator => "Run selected test (salloc/sbatch)",
-            _ => "Run selected test",
-        };
-
-        spank.register_option(SpankOption::new("test").takes_value("test").usage(usage))?;
+        spank.register_option(
+            SpankOption::new("test")
+                .takes_value("test")
+                .usage("Run selected test")
+                .usage_for(Context::Local, "Run selected test (srun)")
+                .usage_for(Context::Allocator, "Run selected test (salloc/sbatch)"),
+        )?;
+        match spank.register_option(SpankOption::new("test")) {
+            Err(SpankError::OptionExists { name }) => assert_eq!(name, "test"),
+            res => panic!("Expected an OptionExists error, got {:?}", res),
+        }
+        spank.register_builtin_options()?;
+        spank.register_option(
+            SpankOption::new("test-mode")
+                .takes_value("mode")
+                .env("SLURM_TEST_MODE")
+                .default("none")
+                .alias("test-old-mode")
+                .deprecated("use --test-mode"),
+        )?;
+        spank.register_option(
+            SpankOption::new("test-number")
+                .takes_value("number")
+                .env("SLURM_TEST_NUMBER")
+                .validate(|value| match value.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("{value} is not a number")),
+                }),
+        )?;
 
         if context == Context::Slurmd {
             info!("Plugin arguments {}", spank.plugin_argv()?.join(","));
@@ -34,12 +56,41 @@ unsafe impl Plugin for SpankTest {
     }
     fn init_post_opt(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
         let context = spank.context()?;
+        if spank.option_count("test-static") > 0 {
+            spank_log_user!(
+                "{:?}: static option: {} ({} occurrences)",
+                context,
+                spank.get_option_values("test-static")?.join(","),
+                spank.option_count("test-static")
+            );
+        }
+        // Values from the environment are validated when they are retrieved
+        spank.get_option_value("test-number")?;
+        debug!("{:?}: debug logs enabled", context);
+
         let Some(test) = spank.get_option_value("test")? else {
             return Ok(());
         };
 
         spank_log_user!("{:?}: selected test: {test}", context);
 
+        if test == "option-defaults" {
+            if let Some((mode, source)) = spank.get_option_value_with_source("test-mode")? {
+                spank_log_user!("{:?}: test mode {mode} from {:?}", context, source);
+            }
+        }
+
+        if test == "option-occurrences" {
+            for occurrence in spank.get_option_occurrences("test") {
+                spank_log_user!(
+                    "{:?}: test option parsed in {:?}, remote: {}",
+                    context,
+                    occurrence.context(),
+                    occurrence.is_remote()
+                );
+            }
+        }
+
         if test == "client-error"
             && (context == slurm_spank::Context::Local
                 || context == slurm_spank::Context::Allocator)
@@ -67,9 +118,67 @@ unsafe impl Plugin for SpankTest {
                 .is_err());
             spank.setenv("EXISTING_VAR2", "Modified value", true)?;
         }
+        if test == "env-map" && (context == slurm_spank::Context::Remote) {
+            let before = spank.job_env_map()?;
+            let mut after = before.clone();
+            after.set("NEW_VALUE", "New value");
+            after.remove("EXISTING_VAR1");
+            let changes = after.diff(&before);
+            spank_log_user!("Env changes: {}", changes.len());
+            spank.apply_env_changes(&changes)?;
+            spank
+                .env_list("TEST_LIST")
+                .prepend("/opt/x/lib")
+                .append("/usr/lib")
+                .remove("/bad")
+                .apply()?;
+            assert!(spank
+                .apply_env_changes(&[slurm_spank::EnvChange::Unset("BAD\0NAME".into())])
+                .is_err());
+        }
+        if test == "env-profile" && (context == slurm_spank::Context::Remote) {
+            let profile = slurm_spank::EnvProfile::parse(
+                "test",
+                "# Test profile\n\
+                 export PROFILE_HOME=\"/opt/${PROFILE_NAME}\"\n\
+                 unset EXISTING_VAR1\n\
+                 prepend_path PATH ${PROFILE_HOME}/bin\n",
+            )?;
+            for change in profile.changes(&spank.job_env_map()?) {
+                spank_log_user!("Profile dry-run: {}", change);
+            }
+            spank.apply_env_profile(&profile)?;
+        }
+        if test == "env-policy" && (context == slurm_spank::Context::Remote) {
+            let policy = slurm_spank::EnvPolicy::from_plugin_args(&[
+                "env_deny_regex=TEST_DENY_.*",
+                "env_allow=TEST_DENY_KEEP",
+                "env_cap_cpus=OMP_NUM_THREADS",
+            ])?;
+            let changes = spank.apply_env_policy(&policy)?;
+            spank_log_user!("Env policy changes: {}", changes.len());
+        }
+        if test == "mount-ns" && (context == slurm_spank::Context::Remote) {
+            let scratch = spank.create_job_dir("/var/tmp")?;
+            let ns = spank.mount_namespace()?;
+            ns.private_dev_shm()?;
+            ns.bind(&scratch, "/mnt")?;
+        }
         if test == "job-control" && context == slurm_spank::Context::Local {
             spank.job_control_setenv("FROM_LOCAL", "42", true)?;
         }
+        if test == "state" {
+            if context == slurm_spank::Context::Local {
+                spank.export_state("greeting", &vec!["hello", "world"])?;
+            }
+            if context == slurm_spank::Context::Remote {
+                let greeting: Vec<String> =
+                    spank.import_state("greeting")?.expect("State should exist");
+                spank_log_user!("Remote: imported state: {}", greeting.join(" "));
+                assert!(spank.import_state::<u32>("greeting").is_err());
+                assert!(spank.import_state::<u32>("missing")?.is_none());
+            }
+        }
         if test == "values" && context == slurm_spank::Context::Remote {
             spank_log_user!("spank_remote_job_id: {}", spank.job_id()?);
             spank_log_user!("spank_remote_job_ncpus: {}", spank.job_ncpus()?);
@@ -168,7 +277,19 @@ unsafe impl Plugin for SpankTest {
         Ok(())
     }
 
-    fn task_init_privileged(&mut self, _spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+    fn task_init_privileged(&mut self, spank: &mut SpankHandle) -> Result<(), Box<dyn Error>> {
+        let Some(test) = spank.get_option_value("test")? else {
+            return Ok(());
+        };
+        if test == "mount-ns" {
+            // Already done by slurmstepd before the task was forked
+            spank.mount_namespace()?.private_dev_shm()?;
+        }
+        if test == "secrets" {
+            let names =
+                spank.inject_secrets(&slurm_spank::SecretStore::new("/tmp/spank-secrets"))?;
+            assert_eq!(names, ["SECRET_TOKEN"]);
+        }
         Ok(())
     }
 
@@ -184,6 +305,27 @@ unsafe impl Plugin for SpankTest {
             spank.prepend_task_argv(vec!["/usr/bin/echo", "Hello"])?;
         }
 
+        if test == "command-policy" {
+            let policy = slurm_spank::CommandPolicy::from_plugin_args(&[
+                "cmd_deny=name:printenv",
+                "cmd_redirect=name:true=/usr/bin/echo",
+                "cmd_deny_message=printenv is disabled",
+            ])?;
+            spank.enforce_command_policy(&policy)?;
+        }
+
+        if test == "task-wrapper" {
+            // Appends to WRAPPED each time the task goes through the wrapper
+            let wrapper = slurm_spank::TaskWrapper::new("/bin/sh")
+                .args([
+                    "-c",
+                    r#"export WRAPPED=${WRAPPED:+$WRAPPED,}wrapped; exec "$@""#,
+                    "wrapper",
+                ])
+                .tasks(|id| id == 0);
+            spank.wrap_task(&wrapper)?;
+        }
+
         Ok(())
     }
 
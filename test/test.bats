@@ -25,6 +25,44 @@ teardown_file() {
 
     [ "$status" -eq 0 ]
 }
+
+@test 'task wrapper ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun -n2 --test=task-wrapper bash -c 'echo task $SLURM_PROCID: ${WRAPPED-no}'
+    assert_line --partial 'task 0: wrapped'
+    assert_line --partial 'task 1: no'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'task wrapper not applied twice' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=task-wrapper /bin/sh -c 'export WRAPPED=${WRAPPED:+$WRAPPED,}wrapped; exec "$@"' wrapper printenv WRAPPED
+    assert_line 'wrapped'
+    refute_line --partial 'wrapped,wrapped'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'task wrapper not skipped by program name' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests bash -c 'mkdir /tmp/bin && cp /usr/bin/sh /tmp/bin/ && srun --test=task-wrapper /tmp/bin/sh -c "export WRAPPED=\${WRAPPED:+\$WRAPPED,}wrapped; exec \"\$@\"" wrapper printenv WRAPPED'
+    assert_line 'wrapped,wrapped'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'command policy denies command' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=command-policy printenv
+    assert_line --partial 'printenv is disabled'
+
+    [ "$status" -ne 0 ]
+}
+
+@test 'command policy redirects command' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=command-policy true redirected
+    assert_line --partial 'true redirected'
+
+    [ "$status" -eq 0 ]
+}
+
 @test 'spank remote values ok' {
     run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests su --pty joe -c "salloc --exclusive bash -c 'srun /bin/true;
     srun /bin/true;
@@ -79,6 +117,32 @@ teardown_file() {
     [ "$status" -eq 0 ]
 }
 
+@test 'plugin help display ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --tests-help /bin/true
+    assert_line --partial 'Options provided by the tests plugin:'
+    assert_line --partial '--test-mode=mode'
+    assert_line --partial 'Environment variable: SLURM_TEST_MODE'
+
+    # The help option is rejected so that the job doesn't run
+    [ "$status" -ne 0 ]
+}
+
+@test 'plugin debug option ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun /bin/true
+    refute_line --partial 'Local: debug logs enabled'
+    [ "$status" -eq 0 ]
+
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --tests-debug=info /bin/true
+    refute_line --partial 'Local: debug logs enabled'
+    [ "$status" -eq 0 ]
+
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --tests-debug /bin/true
+    assert_line --partial 'debug: spank{id="tests"'
+    assert_line --partial 'Local: debug logs enabled'
+
+    [ "$status" -eq 0 ]
+}
+
 @test 'srun error fails' {
     run docker run  --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=client-error /bin/true
     assert_line --partial 'error: Expected an error'
@@ -106,6 +170,67 @@ teardown_file() {
     [ "$status" -eq 0 ]
 }
 
+@test 'static option parsing ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test-static=hello /bin/true
+    assert_line --partial 'Local: static option: hello (1 occurrences)'
+    assert_line --partial 'Remote: static option: hello (1 occurrences)'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'repeated option parsing ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test-static=a --test-static=b /bin/true
+    assert_line --partial 'Local: static option: a,b (2 occurrences)'
+    # Slurm only forwards the last occurrence of each option to slurmstepd
+    assert_line --partial 'Remote: static option: b (1 occurrences)'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'option occurrences ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=option-occurrences /bin/true
+    assert_line --partial 'Local: test option parsed in Some(Local), remote: false'
+    assert_line --partial 'Remote: test option parsed in Some(Remote), remote: true'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'option defaults ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=option-defaults /bin/true
+    assert_line --partial 'Local: test mode none from Default'
+    assert_line --partial 'Remote: test mode none from Default'
+
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests env SLURM_TEST_MODE=fast valgrind -q --log-file=/tmp/valgrind_client.log srun --test=option-defaults /bin/true
+    assert_line --partial 'Local: test mode fast from Environment'
+    assert_line --partial 'Remote: test mode fast from Environment'
+
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests env SLURM_TEST_MODE=fast valgrind -q --log-file=/tmp/valgrind_client.log srun --test=option-defaults --test-mode=slow /bin/true
+    assert_line --partial 'Local: test mode slow from CommandLine'
+    assert_line --partial 'Remote: test mode slow from CommandLine'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'deprecated option alias ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=option-defaults --test-old-mode=slow /bin/true
+    assert_line --partial 'Warning: option --test-old-mode is deprecated: use --test-mode'
+    assert_line --partial 'Local: test mode slow from CommandLine'
+    assert_line --partial 'Remote: test mode slow from CommandLine'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'option validation fails' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test-number=abc /bin/true
+    assert_line --partial 'Invalid value for option --test-number: abc is not a number'
+    [ "$status" -ne 0 ]
+
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests env SLURM_TEST_NUMBER=abc valgrind -q --log-file=/tmp/valgrind_client.log srun /bin/true
+    assert_line --partial 'Invalid value for option --test-number: abc is not a number (from environment variable SLURM_TEST_NUMBER)'
+
+    [ "$status" -ne 0 ]
+}
+
 @test 'plugin argument parsing ok' {
     run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun /bin/true
     assert_line --partial 'Plugin arguments arg1,arg2'
@@ -124,6 +249,36 @@ teardown_file() {
     [ "$status" -eq 0 ]
 }
 
+@test 'job env map ok' {
+    run docker run --privileged --cgroupns=private --rm -e EXISTING_VAR1='Initial value' -e TEST_LIST=/usr/lib::/bad:/opt/y:/opt/y slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=env-map bash -c 'echo -e NEW_VALUE: $NEW_VALUE\\nEXISTING_VAR1: ${EXISTING_VAR1-unset}\\nTEST_LIST: $TEST_LIST'
+    assert_line --partial 'Env changes: 2'
+    assert_line --partial 'TEST_LIST: /opt/x/lib:/usr/lib::/opt/y:/opt/y'
+    assert_line --partial 'NEW_VALUE: New value'
+    assert_line --partial 'EXISTING_VAR1: unset'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'env profile ok' {
+    run docker run --privileged --cgroupns=private --rm -e EXISTING_VAR1='Initial value' -e PROFILE_NAME=gromacs slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=env-profile bash -c 'echo -e PATH: $PATH\\nEXISTING_VAR1: ${EXISTING_VAR1-unset}'
+    assert_line --partial 'Profile dry-run: set PROFILE_HOME=/opt/gromacs'
+    assert_line --partial 'Profile dry-run: unset EXISTING_VAR1'
+    assert_line --partial 'PATH: /opt/gromacs/bin:'
+    assert_line --partial 'EXISTING_VAR1: unset'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'env policy ok' {
+    run docker run --privileged --cgroupns=private --rm -e TEST_DENY_A=1 -e TEST_DENY_KEEP=1 -e OMP_NUM_THREADS=64 slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=env-policy bash -c 'echo -e TEST_DENY_A: ${TEST_DENY_A-unset}\\nTEST_DENY_KEEP: $TEST_DENY_KEEP\\nOMP_NUM_THREADS: $OMP_NUM_THREADS'
+    assert_line --partial 'Env policy changes: 2'
+    assert_line --partial 'TEST_DENY_A: unset'
+    assert_line --partial 'TEST_DENY_KEEP: 1'
+    assert_line --partial 'OMP_NUM_THREADS: 1'
+
+    [ "$status" -eq 0 ]
+}
+
 @test 'job control env ok' {
     run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=job-control /bin/true
     assert_line --partial 'Job control from local ok'
@@ -131,6 +286,157 @@ teardown_file() {
     [ "$status" -eq 0 ]
 }
 
+@test 'state transport ok' {
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=state /bin/true
+    assert_line --partial 'Remote: imported state: hello world'
+    [ "$status" -eq 0 ]
+
+    # The state is not exposed to the job
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --test=state env
+    refute_line --partial 'SPANK_RS_STATE_'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'secret injection ok' {
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests bash -c 'mkdir /tmp/spank-secrets && echo SECRET_TOKEN=s3cr3t > /tmp/spank-secrets/default && chmod 600 /tmp/spank-secrets/default && valgrind -q --log-file=/tmp/valgrind_client.log srun --test=secrets printenv SECRET_TOKEN'
+    assert_line 's3cr3t'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'insecure secret file fails' {
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests bash -c 'mkdir /tmp/spank-secrets && echo SECRET_TOKEN=s3cr3t > /tmp/spank-secrets/default && chmod 644 /tmp/spank-secrets/default && valgrind -q --log-file=/tmp/valgrind_client.log srun --test=secrets printenv SECRET_TOKEN'
+    refute_line 's3cr3t'
+
+    [ "$status" -ne 0 ]
+}
+
+@test 'insecure secret directory fails' {
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests bash -c 'mkdir -p /tmp/spank-secrets/uid && chmod 777 /tmp/spank-secrets/uid && echo SECRET_TOKEN=s3cr3t > /tmp/spank-secrets/uid/0 && chmod 600 /tmp/spank-secrets/uid/0 && valgrind -q --log-file=/tmp/valgrind_client.log srun --test=secrets printenv SECRET_TOKEN'
+    refute_line 's3cr3t'
+
+    [ "$status" -ne 0 ]
+}
+
+@test 'mount namespace ok' {
+    run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests bash -c 'valgrind -q --log-file=/tmp/valgrind_client.log srun --test=mount-ns bash -c "touch /dev/shm/job_marker /mnt/scratch_marker && echo created"; test -e /dev/shm/job_marker && echo shm leaked || echo shm private; ls /var/tmp/*/scratch_marker'
+    assert_line --partial 'created'
+    assert_line --partial 'shm private'
+    assert_line --partial 'scratch_marker'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'job directory ok in user namespace' {
+    # Runs on the host: mounts only require an unprivileged user namespace
+    SKIP_SLURM_BINDINGS=1 cargo build --manifest-path "$BATS_TEST_DIRNAME/Cargo.toml" --bin job_dir
+    base=$(mktemp -d)
+    run unshare -Ur "$BATS_TEST_DIRNAME/target/debug/job_dir" "$base"
+    rm -rf "$base"
+    assert_line --partial 'symlink rejected'
+    assert_line 'job dir mode: 700'
+    assert_line 'bind mounted job dir'
+    refute_line 'bind mounted decoy'
+    assert_line 'repeated bind ignored'
+    assert_line --partial 'conflicting bind rejected'
+    assert_line 'remounted read-only'
+    assert_line 'mounted tmpfs'
+    assert_line --partial 'conflicting tmpfs rejected'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'builder plugin ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --builder-greet=world true
+    assert_line --partial 'Remote: builder greets world'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'plugin stack options namespaced' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --help
+    assert_line --partial '--failfast-first-fail'
+    assert_line --partial '--failfast-second-fail'
+    assert_line --partial '--aggregate-first-fail'
+    assert_line --partial '--aggregate-second-fail'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'plugin stack modules ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun true
+    assert_line --partial 'failfast first ran'
+    assert_line --partial 'failfast second ran'
+    assert_line --partial 'aggregate first ran'
+    assert_line --partial 'aggregate second ran'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'plugin stack error modes ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --failfast-first-fail --aggregate-first-fail true
+    # FailFast stops at the failing module, ContinueAndAggregate runs the next one
+    assert_line --partial 'failfast first reported: failfast first failed'
+    refute_line --partial 'failfast second ran'
+    assert_line --partial 'aggregate first reported: aggregate first failed'
+    assert_line --partial 'aggregate second ran'
+    # Errors are only reported by the failing modules, not again by the stacks
+    [ "$(grep -c 'failfast first failed' <<< "$output")" -eq 1 ]
+    [ "$(grep -c 'aggregate first failed' <<< "$output")" -eq 1 ]
+
+    [ "$status" -ne 0 ]
+}
+
+@test 'foreign plugin options registered' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --help
+    assert_line --partial '--foreign-greet=name'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'foreign plugin callbacks ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --foreign-greet=world true
+    assert_line --partial 'foreign_c init_post_opt: greeting world, argv /build/slurm-spank/test_plugin/c/libforeign_c.so,forwarded'
+    assert_line --partial 'foreign_c user_init: greeting world, argv /build/slurm-spank/test_plugin/c/libforeign_c.so,forwarded'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'plugin instances options registered' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --help
+    assert_line --partial '--instance-a=value'
+    assert_line --partial '--instance-b=value'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'plugin instances ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --instance-a=1 --instance-b=2 true
+    assert_line --partial 'Local: instance a: value 1, argv name=a,first'
+    assert_line --partial 'Local: instance b: value 2, argv name=b,second'
+    assert_line --partial 'Remote: instance a: value 1, argv name=a,first'
+    assert_line --partial 'Remote: instance b: value 2, argv name=b,second'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'prefixed option names ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --help
+    assert_line --partial '--prefixed-greet=name'
+
+    [ "$status" -eq 0 ]
+}
+
+@test 'prefixed option values ok' {
+    run docker run --privileged --cgroupns=private --rm slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --prefixed-greet=world true
+    assert_line --partial 'option exists: prefixed-greet'
+    assert_line --partial 'Local: prefixed greets world'
+    assert_line --partial 'Remote: prefixed greets world'
+
+    [ "$status" -eq 0 ]
+}
+
 @test 'hello example build ok' {
     run docker run --privileged --cgroupns=private --rm  slurm-spank-rs/tests valgrind -q --log-file=/tmp/valgrind_client.log srun --help
     assert_line --partial '--greet=name'